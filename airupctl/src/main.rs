@@ -1,38 +1,419 @@
-use nng::{Socket, Protocol};
-use clap::{App, Arg, SubCommand};
+use ansi_term::Color::*;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libairup::ipc::{
+    self, Client, Error, ErrorCode, Method, PowerAction, Reply, SvcInfo, SvcStatus,
+};
+use std::{
+    env, io,
+    process::{exit, Command},
+    thread,
+    time::{Duration, Instant},
+};
 
+// Exit codes, so scripts can tell failures apart.
+const EXIT_FAILED: i32 = 1;
+const EXIT_NOT_RUNNING: i32 = 3;
+const EXIT_UNREACHABLE: i32 = 4;
+
+fn svc_command<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name).about(about).arg(
+        Arg::with_name("svc")
+            .help("The service to operate on.")
+            .value_name("SVC")
+            .required(true),
+    )
+}
+fn print_error(e: &Error, json: bool) {
+    if json {
+        println!("{}", serde_json::json!({ "error": e }));
+    } else {
+        eprintln!("{}{}", Red.paint(" * "), e);
+    }
+}
+fn paint_status(status: SvcStatus) -> String {
+    let color = match status {
+        SvcStatus::Running => Green,
+        SvcStatus::Readying | SvcStatus::Restarting | SvcStatus::Stopping => Yellow,
+        SvcStatus::Stopped => Red,
+    };
+    color.paint(status.to_string()).to_string()
+}
+fn print_info(info: &SvcInfo) {
+    let mut line = format!(
+        "{} {}",
+        Blue.paint(format!("{:<24}", info.svc)),
+        paint_status(info.status)
+    );
+    if info.pid != 0 {
+        line.push_str(&format!(" (pid {})", info.pid));
+    }
+    if let Some(text) = &info.status_text {
+        line.push_str(&format!(": {}", text));
+    }
+    if let (SvcStatus::Stopped, Some(exit)) = (info.status, info.last_exit) {
+        line.push_str(&format!(" [{}]", exit));
+    }
+    if info.needs_restart {
+        line.push_str(&format!(" {}", Yellow.paint("(needs restart)")));
+    }
+    println!("{}", line);
+}
+// Print the last lines of a service's output, then keep polling for new
+// ones if asked to follow.
+fn logs(client: &Client, args: &ArgMatches, json: bool) -> Result<i32, Error> {
+    let svc = args.value_of("svc").unwrap().to_string();
+    let lines = match args.value_of("lines").map(|a| a.parse::<u32>()) {
+        Some(Ok(a)) => Some(a),
+        Some(Err(_)) => {
+            return Err(Error::new(
+                ErrorCode::BadRequest,
+                "--lines must be a number",
+            ))
+        }
+        None => None,
+    };
+    let mut since = None;
+    loop {
+        let reply = client.call(Method::Logs {
+            svc: svc.clone(),
+            since,
+            lines,
+        })?;
+        if let Reply::Logs { lines, next } = &reply {
+            if json {
+                println!("{}", serde_json::to_string(&reply).unwrap());
+            } else {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            since = Some(*next);
+        }
+        if !args.is_present("follow") {
+            return Ok(0);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+// Start a service and wait until it runs or has failed to, so that the
+// exit code tells how the start went.
+fn start(client: &Client, args: &ArgMatches, json: bool) -> Result<i32, Error> {
+    let svc = args.value_of("svc").unwrap().to_string();
+    let timeout = match args.value_of("timeout").unwrap().parse::<u64>() {
+        Ok(a) => Duration::from_secs(a),
+        Err(_) => {
+            return Err(Error::new(
+                ErrorCode::BadRequest,
+                "--timeout must be a number of seconds",
+            ))
+        }
+    };
+    client.call(Method::Start { svc: svc.clone() })?;
+    if !json {
+        println!(
+            "{}Starting service {}...",
+            Green.paint(" * "),
+            Green.paint(&svc)
+        );
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        let reply = client.call(Method::Status { svc: svc.clone() })?;
+        let info = match &reply {
+            Reply::Status(info) => info,
+            _ => return Ok(0),
+        };
+        if info.status == SvcStatus::Readying && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(200));
+            continue;
+        }
+        if json {
+            println!("{}", serde_json::to_string(&reply).unwrap());
+        }
+        // A service that did its work and exited cleanly started fine.
+        let failure = match (&info.failure, info.status) {
+            (Some(failure), _) => failure.clone(),
+            (None, SvcStatus::Readying) => {
+                format!("it did not start within {} seconds", timeout.as_secs())
+            }
+            // Restarted before it ever ran, so the start failed.
+            (None, SvcStatus::Restarting) => match &info.last_exit {
+                Some(exit) => format!("it {} and is being restarted", exit),
+                None => "it is being restarted".to_string(),
+            },
+            (None, SvcStatus::Stopping) => "it is being stopped".to_string(),
+            _ => return Ok(0),
+        };
+        if !json {
+            eprintln!(
+                "{}Failed to start service {}: {}",
+                Red.paint(" * "),
+                svc,
+                failure
+            );
+        }
+        return Ok(EXIT_FAILED);
+    }
+}
+fn power(action: PowerAction) -> (Method, &'static str, String) {
+    let doing = match action {
+        PowerAction::Poweroff => "Powering off",
+        PowerAction::Reboot | PowerAction::Kexec => "Rebooting",
+        PowerAction::Halt => "Halting",
+        PowerAction::SoftReboot => "Soft-rebooting",
+    };
+    (Method::Power { action }, doing, "the system".to_string())
+}
+fn run(client: &Client, name: &str, args: &ArgMatches, json: bool) -> Result<i32, Error> {
+    match name {
+        "logs" => return logs(client, args, json),
+        "start" => return start(client, args, json),
+        _ => (),
+    }
+    let svc = || args.value_of("svc").unwrap().to_string();
+    let (method, doing, what) = match name {
+        "stop" => (Method::Stop { svc: svc() }, "Stopping service", svc()),
+        "restart" => (Method::Restart { svc: svc() }, "Restarting service", svc()),
+        "status" => (Method::Status { svc: svc() }, "", String::new()),
+        "list" => (Method::List, "", String::new()),
+        "reload" => (Method::Reload, "", String::new()),
+        "milestone" => match args.subcommand() {
+            ("switch", Some(args)) => {
+                let milestone = args.value_of("milestone").unwrap().to_string();
+                (
+                    Method::SwitchMilestone {
+                        milestone: milestone.clone(),
+                    },
+                    "Switching to milestone",
+                    milestone,
+                )
+            }
+            _ => (Method::Milestones, "", String::new()),
+        },
+        "poweroff" => power(PowerAction::Poweroff),
+        "halt" => power(PowerAction::Halt),
+        "reboot" if args.is_present("kexec") => power(PowerAction::Kexec),
+        "reboot" if args.is_present("soft") => power(PowerAction::SoftReboot),
+        "reboot" => power(PowerAction::Reboot),
+        "sys" => match args.value_of("power").map(|a| a.to_lowercase()) {
+            Some(ref a) if a == "off" => power(PowerAction::Poweroff),
+            Some(ref a) if a == "reboot" => power(PowerAction::Reboot),
+            Some(ref a) if a == "halt" => power(PowerAction::Halt),
+            _ => {
+                return Err(Error::new(
+                    ErrorCode::BadRequest,
+                    "--power must be OFF, REBOOT or HALT",
+                ))
+            }
+        },
+        _ => unreachable!(),
+    };
+    let reply = client.call(method)?;
+    if json {
+        println!("{}", serde_json::to_string(&reply).unwrap());
+    }
+    let code = match &reply {
+        Reply::Status(info) if info.status != SvcStatus::Running => EXIT_NOT_RUNNING,
+        Reply::Reload { failed, .. } if !failed.is_empty() => EXIT_FAILED,
+        _ => 0,
+    };
+    if json {
+        return Ok(code);
+    }
+    match reply {
+        Reply::Done if !doing.is_empty() => {
+            println!("{}{} {}...", Green.paint(" * "), doing, Green.paint(what))
+        }
+        Reply::Status(info) => {
+            print_info(&info);
+            if !info.sandbox.is_empty() {
+                println!("{:<24} sandbox: {}", "", info.sandbox.join(", "));
+            }
+        }
+        Reply::List { services } => {
+            for info in services.iter() {
+                print_info(info);
+            }
+        }
+        Reply::Orphans { count } => println!("{}", count),
+        Reply::Milestones {
+            stage,
+            target,
+            reached,
+        } => {
+            println!("{}Stage: {}", Green.paint(" * "), stage);
+            println!("{}Target: {}", Green.paint(" * "), Blue.paint(target));
+            println!("{}Reached: {}", Green.paint(" * "), reached.join(", "));
+        }
+        Reply::Reload {
+            added,
+            changed,
+            needs_restart,
+            failed,
+        } => {
+            for (what, ids) in &[
+                ("Added", &added),
+                ("Changed", &changed),
+                ("Needs restart", &needs_restart),
+            ] {
+                if !ids.is_empty() {
+                    println!("{}{}: {}", Green.paint(" * "), what, ids.join(", "));
+                }
+            }
+            if !failed.is_empty() {
+                eprintln!("{}Failed: {}", Red.paint(" * "), failed.join(", "));
+            }
+        }
+        _ => (),
+    }
+    Ok(code)
+}
+// Checking needs no running airupd: hand it to `airupd --check`, preferring
+// the binary installed next to us.
+fn verify(args: &ArgMatches) -> io::Result<i32> {
+    let airupd = env::current_exe()
+        .ok()
+        .and_then(|a| a.parent().map(|d| d.join("airupd")))
+        .filter(|a| a.exists())
+        .unwrap_or_else(|| "airupd".into());
+    let mut cmd = Command::new(airupd);
+    cmd.arg("--check");
+    if let Some(path) = args.value_of("path") {
+        cmd.arg(path);
+    }
+    Ok(cmd.status()?.code().unwrap_or(EXIT_FAILED))
+}
 fn main() {
     let matches = App::new("Airup Controller")
         .version(env!("CARGO_PKG_VERSION"))
         .about("The controller of airupd init.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("Prints replies as JSON."),
+        )
         .subcommand(
-        	SubCommand::with_name("sys")
-        	    .version(env!("CARGO_PKG_VERSION"))
-        	    .about("To control power or system settings.")
-        	    .arg(
-        	    	Arg::with_name("power")
-        	    	    .long("power")
-        	    	    .help("OFF or REBOOT.")
-        	    	    .value_name("STAT")
-        	    	    .takes_value(true)
-        	    )
-        )
-    .get_matches();
-    let client = Socket::new(Protocol::Req0).unwrap();
-    let addr = "tcp://127.0.0.1:61257";
-    match matches.subcommand() {
-    	("sys", Some(x)) => {
-    		client.dial(addr).unwrap();
-    		let action = x.value_of("power").unwrap().to_lowercase();
-    		let mut msg = String::new();
-    		if action == "off" {
-    			msg.push_str("system poweroff");
-    		} else if action == "reboot" {
-    			msg.push_str("system restart");
-    		}
-    		let msg = msg.as_bytes();
-    		client.send(msg).unwrap();
-    	},
-    	_ => (),
-    };
+            svc_command("start", "Starts a service and waits for it to run.").arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .help("How many seconds to wait for the service to run.")
+                    .value_name("SECS")
+                    .default_value("90"),
+            ),
+        )
+        .subcommand(svc_command("stop", "Stops a service."))
+        .subcommand(svc_command("restart", "Restarts a service."))
+        .subcommand(svc_command("status", "Shows the status of a service."))
+        .subcommand(SubCommand::with_name("list").about("Lists supervised services."))
+        .subcommand(
+            SubCommand::with_name("reload")
+                .about("Reads service definitions again and starts new ones."),
+        )
+        .subcommand(
+            SubCommand::with_name("milestone")
+                .about("Shows or switches the milestone.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("switch")
+                        .about("Reaches a milestone, stopping what it does not need.")
+                        .arg(
+                            Arg::with_name("milestone")
+                                .help("The milestone to reach.")
+                                .value_name("MILESTONE")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Shows the stage and the milestones reached."),
+                ),
+        )
+        .subcommand(
+            svc_command("logs", "Shows the output of a service.")
+                .arg(
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("Keeps printing new output."),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .short("n")
+                        .long("lines")
+                        .help("How many past lines to show.")
+                        .value_name("N")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks configuration files without airupd running.")
+                .arg(
+                    Arg::with_name("path")
+                        .help("A file or an airup_home; defaults to the installed configuration.")
+                        .index(1),
+                ),
+        )
+        .subcommand(SubCommand::with_name("poweroff").about("Powers the system off."))
+        .subcommand(
+            SubCommand::with_name("reboot")
+                .about("Reboots the system.")
+                .arg(
+                    Arg::with_name("kexec")
+                        .long("kexec")
+                        .help("Boots the kernel loaded for kexec, skipping firmware."),
+                )
+                .arg(
+                    Arg::with_name("soft")
+                        .long("soft")
+                        .conflicts_with("kexec")
+                        .help("Only starts userspace over, keeping the kernel."),
+                ),
+        )
+        .subcommand(SubCommand::with_name("halt").about("Halts the system."))
+        .subcommand(
+            SubCommand::with_name("sys")
+                .version(env!("CARGO_PKG_VERSION"))
+                .about("To control power or system settings.")
+                .arg(
+                    Arg::with_name("power")
+                        .long("power")
+                        .help("OFF, REBOOT or HALT.")
+                        .value_name("STAT")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .get_matches();
+    let (name, args) = matches.subcommand();
+    let args = args.unwrap();
+    let json = matches.is_present("json")
+        || args.is_present("json")
+        || args
+            .subcommand()
+            .1
+            .map(|a| a.is_present("json"))
+            .unwrap_or(false);
+    if name == "verify" {
+        match verify(args) {
+            Ok(code) => exit(code),
+            Err(e) => {
+                eprintln!("{}Failed to run airupd: {}", Red.paint(" * "), e);
+                exit(EXIT_FAILED);
+            }
+        }
+    }
+    let result = Client::connect(&ipc::address()).and_then(|c| run(&c, name, args, json));
+    match result {
+        Ok(code) => exit(code),
+        Err(e) => {
+            print_error(&e, json);
+            exit(match e.code {
+                ErrorCode::Unreachable => EXIT_UNREACHABLE,
+                _ => EXIT_FAILED,
+            });
+        }
+    }
 }
@@ -0,0 +1,163 @@
+use crate::config::{NumOrStr, ServiceConfig};
+use libc::{
+    c_int, c_void, mode_t, rlim_t, rlimit, O_CLOEXEC, O_WRONLY, PRIO_PROCESS, RLIM_INFINITY,
+};
+use std::{convert::TryInto, ffi::CString, io};
+
+// Process attributes of a service's commands, checked when the service is
+// loaded and applied in the forked child before it drops privileges.
+#[derive(Clone, Default)]
+pub struct ProcAttrs {
+    working_dir: Option<CString>,
+    chroot: Option<CString>,
+    umask: Option<mode_t>,
+    nice: Option<c_int>,
+    // Pre-formatted, so the child only has to write it.
+    oom_score_adj: Option<Vec<u8>>,
+    limits: Vec<(c_int, rlimit)>,
+}
+
+const RLIMITS: &[(&str, c_int)] = &[
+    ("as", libc::RLIMIT_AS as c_int),
+    ("core", libc::RLIMIT_CORE as c_int),
+    ("cpu", libc::RLIMIT_CPU as c_int),
+    ("data", libc::RLIMIT_DATA as c_int),
+    ("fsize", libc::RLIMIT_FSIZE as c_int),
+    ("locks", libc::RLIMIT_LOCKS as c_int),
+    ("memlock", libc::RLIMIT_MEMLOCK as c_int),
+    ("msgqueue", libc::RLIMIT_MSGQUEUE as c_int),
+    ("nice", libc::RLIMIT_NICE as c_int),
+    ("nofile", libc::RLIMIT_NOFILE as c_int),
+    ("nproc", libc::RLIMIT_NPROC as c_int),
+    ("rss", libc::RLIMIT_RSS as c_int),
+    ("rtprio", libc::RLIMIT_RTPRIO as c_int),
+    ("rttime", libc::RLIMIT_RTTIME as c_int),
+    ("sigpending", libc::RLIMIT_SIGPENDING as c_int),
+    ("stack", libc::RLIMIT_STACK as c_int),
+];
+
+impl ProcAttrs {
+    pub fn new(cfg: &ServiceConfig) -> Result<ProcAttrs, String> {
+        let mut attrs = ProcAttrs::default();
+        if let Some(val) = &cfg.working_dir {
+            attrs.working_dir = Some(abs_path("working_dir", val)?);
+        }
+        if let Some(val) = &cfg.chroot {
+            attrs.chroot = Some(abs_path("chroot", val)?);
+        }
+        if let Some(val) = &cfg.umask {
+            // An integer (0o022 in TOML) or an octal string ("022").
+            let umask = match val {
+                NumOrStr::Num(a) => Some(*a),
+                NumOrStr::Str(a) => i64::from_str_radix(a, 8).ok(),
+            };
+            match umask.filter(|a| (0..=0o777).contains(a)) {
+                Some(a) => attrs.umask = Some(a as mode_t),
+                None => return Err(format!("umask {} is not an octal mode", val)),
+            }
+        }
+        if let Some(val) = cfg.nice {
+            attrs.nice = Some(int_in("nice", val, -20, 19)? as c_int);
+        }
+        if let Some(val) = cfg.oom_score_adj {
+            let adj = int_in("oom_score_adj", val, -1000, 1000)?;
+            attrs.oom_score_adj = Some(adj.to_string().into_bytes());
+        }
+        for (name, val) in &cfg.limits {
+            let resource = RLIMITS
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, r)| *r)
+                .ok_or_else(|| format!("unknown limit {}", name))?;
+            attrs.limits.push((resource, rlimit_of(name, val)?));
+        }
+        Ok(attrs)
+    }
+    // Runs in the forked child, so it must stick to plain system calls.
+    pub fn apply(&self) -> io::Result<()> {
+        let check = |rv: c_int| {
+            if rv < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        unsafe {
+            for (resource, limit) in &self.limits {
+                check(libc::setrlimit(*resource as _, limit))?;
+            }
+            if let Some(nice) = self.nice {
+                check(libc::setpriority(PRIO_PROCESS as _, 0, nice))?;
+            }
+            if let Some(adj) = &self.oom_score_adj {
+                let fd = libc::open(
+                    b"/proc/self/oom_score_adj\0".as_ptr() as *const _,
+                    O_WRONLY | O_CLOEXEC,
+                );
+                check(fd)?;
+                let rv = libc::write(fd, adj.as_ptr() as *const c_void, adj.len());
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                if rv < 0 {
+                    return Err(err);
+                }
+            }
+            if let Some(root) = &self.chroot {
+                check(libc::chroot(root.as_ptr()))?;
+                if self.working_dir.is_none() {
+                    check(libc::chdir(b"/\0".as_ptr() as *const _))?;
+                }
+            }
+            // Inside the new root, if there is one.
+            if let Some(dir) = &self.working_dir {
+                check(libc::chdir(dir.as_ptr()))?;
+            }
+            if let Some(umask) = self.umask {
+                libc::umask(umask);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn abs_path(key: &str, val: &str) -> Result<CString, String> {
+    if !val.starts_with('/') {
+        return Err(format!("{} must be an absolute path", key));
+    }
+    CString::new(val).map_err(|_| format!("{} contains a NUL byte", key))
+}
+fn int_in(key: &str, val: i64, min: i64, max: i64) -> Result<i64, String> {
+    if !(min..=max).contains(&val) {
+        return Err(format!(
+            "{} must be an integer from {} to {}",
+            key, min, max
+        ));
+    }
+    Ok(val)
+}
+// A number or "unlimited" sets both limits; "soft:hard" sets them apart.
+fn rlimit_of(name: &str, val: &NumOrStr) -> Result<rlimit, String> {
+    let one = |a: &str| -> Option<rlim_t> {
+        match a {
+            "unlimited" | "infinity" => Some(RLIM_INFINITY),
+            a => a.parse().ok(),
+        }
+    };
+    let (soft, hard) = match val {
+        NumOrStr::Num(a) => {
+            let a = (*a).try_into().ok();
+            (a, a)
+        }
+        NumOrStr::Str(a) => match a.split_once(':') {
+            Some((soft, hard)) => (one(soft), one(hard)),
+            None => (one(a), one(a)),
+        },
+    };
+    match (soft, hard) {
+        (Some(soft), Some(hard)) if soft <= hard => Ok(rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        }),
+        _ => Err(format!("limit {} = {} is invalid", name, val)),
+    }
+}
@@ -0,0 +1,125 @@
+use libc::{c_void, pid_t, O_CLOEXEC, O_WRONLY, SIGKILL};
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Controllers behind memory_max, cpu_weight and pids_max.
+const CONTROLLERS: &str = "+cpu +memory +pids";
+
+// The part of the cgroup v2 hierarchy airupd manages: one directory per
+// service below an `airup` directory next to airupd's own cgroup.
+pub struct Hierarchy {
+    base: PathBuf,
+}
+#[derive(Clone)]
+pub struct Cgroup {
+    path: PathBuf,
+    procs: CString,
+}
+
+impl Hierarchy {
+    pub fn open() -> io::Result<Hierarchy> {
+        let root = Path::new(CGROUP_ROOT);
+        if !root.join("cgroup.controllers").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no cgroup v2 hierarchy at /sys/fs/cgroup",
+            ));
+        }
+        // "0::/path" is the v2 entry of /proc/self/cgroup.
+        let own = fs::read_to_string("/proc/self/cgroup")?
+            .lines()
+            .find_map(|l| l.strip_prefix("0::").map(|p| p.to_string()))
+            .unwrap_or_else(|| "/".to_string());
+        let parent = root.join(own.trim_start_matches('/'));
+        let base = parent.join("airup");
+        fs::create_dir_all(&base)?;
+        // Limits need the controllers on the way down; without them services
+        // are still contained.
+        if fs::write(parent.join("cgroup.subtree_control"), CONTROLLERS).is_err()
+            || fs::write(base.join("cgroup.subtree_control"), CONTROLLERS).is_err()
+        {
+            warn!("Failed to enable cgroup controllers: resource limits may not apply!");
+        }
+        Ok(Hierarchy { base })
+    }
+    pub fn create(&self, id: &str) -> io::Result<Cgroup> {
+        let path = self.base.join(id);
+        if let Err(e) = fs::create_dir(&path) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+        let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Cgroup { path, procs })
+    }
+}
+impl Cgroup {
+    pub fn set(&self, key: &str, val: &str) -> io::Result<()> {
+        fs::write(self.path.join(key), val)
+    }
+    pub fn events(&self) -> PathBuf {
+        self.path.join("cgroup.events")
+    }
+    pub fn populated(&self) -> bool {
+        fs::read_to_string(self.events())
+            .map(|a| a.lines().any(|l| l == "populated 1"))
+            .unwrap_or(false)
+    }
+    // Only succeeds once the cgroup is empty.
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir(&self.path)
+    }
+    pub fn pids(&self) -> Vec<pid_t> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .map(|a| a.lines().filter_map(|l| l.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+    // Signal every process of the cgroup, and `pid` too if it lives outside.
+    pub fn signal(&self, sig: i32, pid: pid_t) {
+        let pids = self.pids();
+        for i in &pids {
+            unsafe {
+                libc::kill(*i, sig);
+            }
+        }
+        if pid > 0 && !pids.contains(&pid) {
+            unsafe {
+                libc::kill(pid, sig);
+            }
+        }
+    }
+    // cgroup.kill also catches processes forked while we are killing; it
+    // needs Linux 5.14, so fall back to signalling one by one.
+    pub fn kill(&self, pid: pid_t) {
+        if self.set("cgroup.kill", "1").is_err() {
+            self.signal(SIGKILL, pid);
+        } else if pid > 0 {
+            unsafe {
+                libc::kill(pid, SIGKILL);
+            }
+        }
+    }
+    // Move the calling process into the cgroup. Runs in a forked child, so
+    // it must stick to plain system calls.
+    pub fn join(&self) -> io::Result<()> {
+        unsafe {
+            let fd = libc::open(self.procs.as_ptr(), O_WRONLY | O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let rv = libc::write(fd, b"0".as_ptr() as *const c_void, 1);
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            if rv < 0 {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,966 @@
+use crate::{
+    attrs::ProcAttrs, creds::Group, environ::Environ, journal::Level, sandbox::Sandbox, Cmdline,
+    User,
+};
+use libc::c_int;
+use serde::{
+    de::{self, value, DeserializeOwned, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml::{map::Map, Value};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Service,
+    Milestone,
+    AirupConf,
+}
+#[derive(Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "a signal number, a command line or an argv array"
+)]
+pub enum StopWay {
+    Signal(c_int),
+    Command(Cmdline),
+}
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a number or a string")]
+pub enum NumOrStr {
+    Num(i64),
+    Str(String),
+}
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a path or an array of paths")]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "milliseconds or a duration such as \"5s\"")]
+enum RawDuration {
+    Millis(u64),
+    Text(String),
+}
+#[derive(PartialEq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    Always,
+    OnFailure,
+    OnAbnormal,
+    Never,
+}
+#[derive(PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ready {
+    Timeout,
+    Notify,
+}
+#[derive(PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    Continue,
+    Abort,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+// The [svc] table of a .svc file. A new key is a field here plus its
+// default below; the key list for unknown-key warnings follows from it.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    pub prompt: Option<String>,
+    pub description: String,
+    #[serde(deserialize_with = "strings_only")]
+    pub env_list: BTreeMap<String, String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub env_file: Vec<String>,
+    pub env_clear: bool,
+    pub env_expand: bool,
+    pub take_io: bool,
+    pub user: Option<User>,
+    pub action_user: Option<User>,
+    pub group: Option<Group>,
+    pub supplementary_groups: Vec<Group>,
+    pub dependencies: Vec<String>,
+    pub provides: Vec<String>,
+    pub pre_exec: Option<Cmdline>,
+    pub exec: Option<Cmdline>,
+    pub pid_file: Option<String>,
+    pub pre_stop: Option<Cmdline>,
+    pub stop_way: StopWay,
+    pub cleanup: Option<Cmdline>,
+    pub pre_restart: Option<Cmdline>,
+    pub restart_way: Option<StopWay>,
+    pub cleanup_on_restart: bool,
+    pub restart: Restart,
+    pub success_exit_codes: Vec<i32>,
+    #[serde(deserialize_with = "duration")]
+    pub restart_delay: Duration,
+    #[serde(deserialize_with = "duration")]
+    pub restart_delay_max: Duration,
+    pub retry_time: u32,
+    #[serde(deserialize_with = "duration")]
+    pub retry_window: Duration,
+    pub ready: Ready,
+    #[serde(deserialize_with = "opt_duration")]
+    pub ready_timeout: Option<Duration>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub watchdog_timeout: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub kill_timeout: Duration,
+    pub memory_max: Option<NumOrStr>,
+    pub cpu_weight: Option<NumOrStr>,
+    pub pids_max: Option<NumOrStr>,
+    pub working_dir: Option<String>,
+    pub chroot: Option<String>,
+    pub umask: Option<NumOrStr>,
+    pub nice: Option<i64>,
+    pub oom_score_adj: Option<i64>,
+    pub limits: BTreeMap<String, NumOrStr>,
+    pub private_tmp: bool,
+    pub private_network: bool,
+    pub read_only_paths: Vec<String>,
+    pub no_new_privileges: bool,
+    pub capability_bounding_set: Option<Vec<String>>,
+    pub seccomp_allow: Option<Vec<String>>,
+}
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct MilestoneConfig {
+    pub prompt: Option<String>,
+    pub description: String,
+    pub paral: bool,
+    #[serde(deserialize_with = "strings_only")]
+    pub env_list: BTreeMap<String, String>,
+    pub pre_exec: Option<String>,
+    pub dependencies: Vec<String>,
+    pub order: Vec<String>,
+    pub on_failure: OnFailure,
+}
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct AirupConfig {
+    pub osname: String,
+    pub airup_home: String,
+    pub env_path: Option<String>,
+    pub prestart_paral: bool,
+    pub control_groups: Vec<String>,
+    pub power_groups: Vec<String>,
+    pub providers: HashMap<String, String>,
+    pub log_dir: PathBuf,
+    pub log_max_size: u64,
+    pub log_rotate: u32,
+    pub log_lines: usize,
+    pub log_level: Level,
+    pub color: Color,
+    pub boot_log: PathBuf,
+    pub fallback_milestone: String,
+    pub rescue_shell: String,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> ServiceConfig {
+        ServiceConfig {
+            prompt: None,
+            description: "An airup service".to_string(),
+            env_list: BTreeMap::new(),
+            env_file: Vec::new(),
+            env_clear: false,
+            env_expand: false,
+            take_io: true,
+            user: None,
+            action_user: None,
+            group: None,
+            supplementary_groups: Vec::new(),
+            dependencies: Vec::new(),
+            provides: Vec::new(),
+            pre_exec: None,
+            exec: None,
+            pid_file: None,
+            pre_stop: None,
+            stop_way: StopWay::Signal(libc::SIGTERM),
+            cleanup: None,
+            pre_restart: None,
+            restart_way: None,
+            cleanup_on_restart: true,
+            restart: Restart::Always,
+            success_exit_codes: vec![0],
+            restart_delay: Duration::from_millis(100),
+            restart_delay_max: Duration::from_secs(10),
+            retry_time: 3,
+            retry_window: Duration::from_secs(10),
+            ready: Ready::Timeout,
+            ready_timeout: None,
+            watchdog_timeout: None,
+            kill_timeout: Duration::from_secs(5),
+            memory_max: None,
+            cpu_weight: None,
+            pids_max: None,
+            working_dir: None,
+            chroot: None,
+            umask: None,
+            nice: None,
+            oom_score_adj: None,
+            limits: BTreeMap::new(),
+            private_tmp: false,
+            private_network: false,
+            read_only_paths: Vec::new(),
+            no_new_privileges: false,
+            capability_bounding_set: None,
+            seccomp_allow: None,
+        }
+    }
+}
+impl Default for MilestoneConfig {
+    fn default() -> MilestoneConfig {
+        MilestoneConfig {
+            prompt: None,
+            description: "An airup milestone".to_string(),
+            paral: true,
+            env_list: BTreeMap::new(),
+            pre_exec: None,
+            dependencies: Vec::new(),
+            order: Vec::new(),
+            on_failure: OnFailure::Continue,
+        }
+    }
+}
+impl Default for AirupConfig {
+    fn default() -> AirupConfig {
+        AirupConfig {
+            osname: "Unknown OS".to_string(),
+            airup_home: "/etc/airup.d".to_string(),
+            env_path: None,
+            prestart_paral: false,
+            control_groups: Vec::new(),
+            power_groups: Vec::new(),
+            providers: HashMap::new(),
+            log_dir: PathBuf::from("/var/log/airup"),
+            log_max_size: 1048576,
+            log_rotate: 3,
+            log_lines: 1000,
+            log_level: Level::Info,
+            color: Color::Auto,
+            boot_log: PathBuf::from("/var/log/airup-boot.log"),
+            fallback_milestone: "rescue".to_string(),
+            rescue_shell: "/bin/sh".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceFile {
+    svc: ServiceConfig,
+}
+// Only deserialized whole to check a file; airupd itself reads these
+// sections key by key, see `lenient`.
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct MilestoneFile {
+    #[serde(default)]
+    milestone: MilestoneConfig,
+}
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct AirupConfFile {
+    #[serde(default)]
+    airup: AirupConfig,
+}
+
+impl Display for NumOrStr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NumOrStr::Num(a) => write!(f, "{}", a),
+            NumOrStr::Str(a) => write!(f, "{}", a),
+        }
+    }
+}
+impl Kind {
+    // Going by the file name: services end in .svc, milestones are
+    // described by milestone.toml, and anything else is taken as airup.conf.
+    pub fn of(path: &Path) -> Kind {
+        if path.extension().map(|e| e == "svc").unwrap_or(false) {
+            Kind::Service
+        } else if path
+            .file_name()
+            .map(|n| n == "milestone.toml")
+            .unwrap_or(false)
+        {
+            Kind::Milestone
+        } else {
+            Kind::AirupConf
+        }
+    }
+    fn section(self) -> &'static str {
+        match self {
+            Kind::Service => "svc",
+            Kind::Milestone => "milestone",
+            Kind::AirupConf => "airup",
+        }
+    }
+    fn keys(self) -> &'static [&'static str] {
+        match self {
+            Kind::Service => fields_of::<ServiceConfig>(),
+            Kind::Milestone => fields_of::<MilestoneConfig>(),
+            Kind::AirupConf => fields_of::<AirupConfig>(),
+        }
+    }
+}
+impl ServiceConfig {
+    // Values and combinations of keys their types alone do not rule out.
+    pub fn validate(&self) -> Result<(), String> {
+        let watchdog = self.watchdog_timeout.filter(|t| !t.is_zero());
+        if watchdog.is_some() && self.ready != Ready::Notify {
+            return Err("watchdog_timeout needs ready = \"notify\"".to_string());
+        }
+        let environ = Environ {
+            expand: self.env_expand,
+            ..Environ::new(&self.env_list)
+        };
+        environ.check()?;
+        if let Some(weight) = &self.cpu_weight {
+            match weight.to_string().parse::<u32>() {
+                Ok(1..=10000) => (),
+                _ => {
+                    return Err(format!(
+                        "cpu_weight must be between 1 and 10000, not {}",
+                        weight
+                    ))
+                }
+            }
+        }
+        if let Some(max) = &self.memory_max {
+            if !is_memory_size(&max.to_string()) {
+                return Err(format!(
+                    "memory_max must be \"max\" or bytes with an optional K, M, G or T suffix, not {}",
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+impl Report {
+    pub fn log(&self) {
+        for warning in &self.warnings {
+            warn!("{}", warning);
+        }
+        for error in &self.errors {
+            error!("{}", error);
+        }
+    }
+}
+
+// A service is only loaded if its file is valid as a whole. The [svc]
+// table comes along, to tell later what a reload changed.
+pub fn read_service(path: &Path, report: &mut Report) -> Option<(ServiceConfig, Value)> {
+    let text = match fs::read_to_string(path) {
+        Ok(a) => a,
+        Err(e) => {
+            report.errors.push(format!("{}: {}", path.display(), e));
+            return None;
+        }
+    };
+    let (file, value) = parse::<ServiceFile>(Kind::Service, path, &text, report)?;
+    Some((file.svc, value.get("svc").cloned()?))
+}
+// airupd boots without these files, so they are read leniently.
+pub fn read_milestone(path: &Path, report: &mut Report) -> MilestoneConfig {
+    lenient(Kind::Milestone, path, report)
+}
+pub fn read_airup_conf(path: &Path, report: &mut Report) -> AirupConfig {
+    lenient(Kind::AirupConf, path, report)
+}
+
+// Check one file. Type errors point at the key and line; unknown keys are
+// only warned about, since they do no harm beyond being ignored.
+pub fn check(kind: Kind, path: &Path, text: &str) -> Report {
+    let mut report = Report::default();
+    match kind {
+        Kind::Service => {
+            // Values whose type is right may still be unusable.
+            if let Some((file, _)) = parse::<ServiceFile>(kind, path, text, &mut report) {
+                let usable = file.svc.validate().and_then(|_| ProcAttrs::new(&file.svc));
+                if let Err(e) = usable.and_then(|_| Sandbox::new(&file.svc)) {
+                    report.errors.push(format!("{}: {}", path.display(), e));
+                }
+            }
+        }
+        Kind::Milestone => {
+            parse::<MilestoneFile>(kind, path, text, &mut report);
+        }
+        Kind::AirupConf => {
+            parse::<AirupConfFile>(kind, path, text, &mut report);
+        }
+    }
+    report
+}
+// What memory.max takes: "max", or a number of bytes with an optional
+// K, M, G or T suffix.
+fn is_memory_size(text: &str) -> bool {
+    if text == "max" {
+        return true;
+    }
+    let digits = match text.char_indices().last() {
+        Some((i, c)) if "KMGTkmgt".contains(c) => &text[..i],
+        _ => text,
+    };
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+fn parse<T: DeserializeOwned>(
+    kind: Kind,
+    path: &Path,
+    text: &str,
+    report: &mut Report,
+) -> Option<(T, Value)> {
+    let value: Value = match text.parse() {
+        Ok(a) => a,
+        Err(e) => {
+            report.errors.push(format!("{}: {}", path.display(), e));
+            return None;
+        }
+    };
+    unknown_keys(kind, path, text, &value, report);
+    skipped_env(kind, path, text, &value, report);
+    match toml::from_str(text) {
+        Ok(a) => Some((a, value)),
+        Err(e) => {
+            report.errors.push(format!("{}: {}", path.display(), e));
+            None
+        }
+    }
+}
+// Keys that do not fit are dropped one by one, so a typo in one of them
+// only costs that key its value.
+fn lenient<T: DeserializeOwned + Default>(kind: Kind, path: &Path, report: &mut Report) -> T {
+    let text = match fs::read_to_string(path) {
+        Ok(a) => a,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return T::default(),
+        Err(e) => {
+            report.errors.push(format!("{}: {}", path.display(), e));
+            return T::default();
+        }
+    };
+    let value: Value = match text.parse() {
+        Ok(a) => a,
+        Err(e) => {
+            report.errors.push(format!("{}: {}", path.display(), e));
+            return T::default();
+        }
+    };
+    unknown_keys(kind, path, &text, &value, report);
+    skipped_env(kind, path, &text, &value, report);
+    let section = kind.section();
+    let table = match value.get(section) {
+        Some(Value::Table(a)) => a.clone(),
+        Some(_) => {
+            report.errors.push(format!(
+                "{}: {} must be a table",
+                at(path, &text, None, section),
+                section
+            ));
+            return T::default();
+        }
+        None => return T::default(),
+    };
+    let table: Map<String, Value> = table
+        .into_iter()
+        .filter(|(key, val)| {
+            let mut one = Map::new();
+            one.insert(key.clone(), val.clone());
+            match Value::Table(one).try_into::<T>() {
+                Ok(_) => true,
+                Err(e) => {
+                    report.errors.push(format!(
+                        "{}: {}.{}: {}; using the default",
+                        at(path, &text, Some(section), key),
+                        section,
+                        key,
+                        e
+                    ));
+                    false
+                }
+            }
+        })
+        .collect();
+    Value::Table(table).try_into().unwrap_or_default()
+}
+// env_list values that are not strings are left out, see `strings_only`.
+fn skipped_env(kind: Kind, path: &Path, text: &str, value: &Value, report: &mut Report) {
+    let section = kind.section();
+    let env_list = match value.get(section).and_then(|a| a.get("env_list")) {
+        Some(Value::Table(a)) => a,
+        _ => return,
+    };
+    let table = format!("{}.env_list", section);
+    for (key, val) in env_list {
+        if val.is_str() {
+            continue;
+        }
+        // A [svc.env_list] table has a line per key, an inline one does not.
+        let mut place = at(path, text, Some(&table), key);
+        if place == path.display().to_string() {
+            place = at(path, text, Some(section), "env_list");
+        }
+        report.warnings.push(format!(
+            "{}: {}.{} is not a string; skipping it",
+            place, table, key
+        ));
+    }
+}
+fn unknown_keys(kind: Kind, path: &Path, text: &str, value: &Value, report: &mut Report) {
+    let section = kind.section();
+    for (name, table) in value.as_table().into_iter().flatten() {
+        if name != section {
+            report.warnings.push(format!(
+                "{}: unknown table [{}]",
+                at(path, text, None, name),
+                name
+            ));
+            continue;
+        }
+        for key in table.as_table().into_iter().flat_map(|t| t.keys()) {
+            if kind.keys().contains(&key.as_str()) {
+                continue;
+            }
+            let mut warning = format!(
+                "{}: unknown key {}.{}",
+                at(path, text, Some(section), key),
+                section,
+                key
+            );
+            if let Some(near) = kind.keys().iter().find(|k| distance(k, key) <= 2) {
+                warning.push_str(&format!(" (did you mean {}?)", near));
+            }
+            report.warnings.push(warning);
+        }
+    }
+}
+
+// `airupd --check`: check files, or whole airup_home trees, without
+// starting anything. Returns the exit code.
+pub fn check_all(paths: &[PathBuf]) -> i32 {
+    let mut report = Report::default();
+    let mut files = 0;
+    for path in paths {
+        if path.is_dir() {
+            files += check_home(path, &mut report);
+        } else {
+            check_file(path, &mut report);
+            files += 1;
+        }
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+    println!(
+        "{} files checked: {} errors, {} warnings.",
+        files,
+        report.errors.len(),
+        report.warnings.len()
+    );
+    if report.errors.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+fn check_file(path: &Path, report: &mut Report) -> Option<Value> {
+    let text = match fs::read_to_string(path) {
+        Ok(a) => a,
+        Err(e) => {
+            report.errors.push(format!("{}: {}", path.display(), e));
+            return None;
+        }
+    };
+    let mut checked = check(Kind::of(path), path, &text);
+    report.errors.append(&mut checked.errors);
+    report.warnings.append(&mut checked.warnings);
+    text.parse().ok()
+}
+// Every service and milestone, and whether what they refer to exists.
+fn check_home(home: &Path, report: &mut Report) -> usize {
+    let milestones = home.join("milestones");
+    let dirs: Vec<PathBuf> = fs::read_dir(&milestones)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    let mut files = 0;
+    // Milestones mostly link to files under svc, which need no second look.
+    let mut seen: Vec<PathBuf> = Vec::new();
+    let mut services: Vec<(PathBuf, Value)> = Vec::new();
+    let svc_files = dir_files(&home.join("svc"), "svc")
+        .into_iter()
+        .chain(dirs.iter().flat_map(|d| dir_files(d, "svc")));
+    for path in svc_files {
+        let real = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if seen.contains(&real) {
+            continue;
+        }
+        seen.push(real);
+        files += 1;
+        if let Some(value) = check_file(&path, report) {
+            services.push((path, value));
+        }
+    }
+    for (path, value) in &services {
+        for dep in strings(value, "svc", "dependencies") {
+            let found = match dep.strip_prefix("alias::") {
+                Some(name) => services
+                    .iter()
+                    .any(|(_, v)| strings(v, "svc", "provides").iter().any(|p| p == name)),
+                None => home.join("svc").join(format!("{}.svc", dep)).exists(),
+            };
+            if !found {
+                report.errors.push(format!(
+                    "{}: dependency {} does not exist",
+                    path.display(),
+                    dep
+                ));
+            }
+        }
+    }
+    for dir in &dirs {
+        let toml = dir.join("milestone.toml");
+        if !toml.exists() {
+            continue;
+        }
+        files += 1;
+        let value = match check_file(&toml, report) {
+            Some(a) => a,
+            None => continue,
+        };
+        for dep in strings(&value, "milestone", "dependencies") {
+            if !milestones.join(&dep).is_dir() {
+                report.errors.push(format!(
+                    "{}: dependency {} is not a milestone",
+                    toml.display(),
+                    dep
+                ));
+            }
+        }
+        for id in strings(&value, "milestone", "order") {
+            if !dir.join(format!("{}.svc", id)).exists() {
+                report.errors.push(format!(
+                    "{}: service {} in order is not part of the milestone",
+                    toml.display(),
+                    id
+                ));
+            }
+        }
+    }
+    files
+}
+fn dir_files(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().map(|e| e == ext).unwrap_or(false))
+        .collect();
+    files.sort();
+    files
+}
+fn strings(value: &Value, section: &str, key: &str) -> Vec<String> {
+    value
+        .get(section)
+        .and_then(|s| s.get(key))
+        .and_then(|a| a.as_array())
+        .map(|a| {
+            a.iter()
+                .filter_map(|i| i.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+// "path:line" of a key (or with no section, a table header), if found.
+fn at(path: &Path, text: &str, section: Option<&str>, key: &str) -> String {
+    let mut table: Option<&str> = None;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header.trim_end_matches(']').trim();
+            if section.is_none() && header == key {
+                return format!("{}:{}", path.display(), n + 1);
+            }
+            table = Some(header);
+            continue;
+        }
+        let found = line
+            .strip_prefix(key)
+            .map(|rest| rest.trim_start().starts_with('='))
+            .unwrap_or(false);
+        if found && section.is_some() && table == section {
+            return format!("{}:{}", path.display(), n + 1);
+        }
+    }
+    path.display().to_string()
+}
+// Edit distance, to suggest the key a typo was meant to be.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(cur)
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+// A number is milliseconds; a string is a sum of amounts with units, as in
+// "1m30s" or "250ms".
+fn parse_duration(text: &str) -> Option<Duration> {
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut ms: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let letters = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match rest[..letters].trim() {
+            "ms" => 1,
+            "s" => 1000,
+            "m" | "min" => 60_000,
+            "h" => 3_600_000,
+            _ => return None,
+        };
+        rest = &rest[letters..];
+        ms = ms.checked_add(amount.checked_mul(unit)?)?;
+    }
+    Some(Duration::from_millis(ms))
+}
+fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    match RawDuration::deserialize(d)? {
+        RawDuration::Millis(a) => Ok(Duration::from_millis(a)),
+        RawDuration::Text(a) => parse_duration(&a).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Str(&a), &"a duration such as \"5s\"")
+        }),
+    }
+}
+fn opt_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    duration(d).map(Some)
+}
+fn strings_only<'de, D: Deserializer<'de>>(d: D) -> Result<BTreeMap<String, String>, D::Error> {
+    Ok(BTreeMap::<String, Value>::deserialize(d)?
+        .into_iter()
+        .filter_map(|(key, val)| match val {
+            Value::String(a) => Some((key, a)),
+            _ => None,
+        })
+        .collect())
+}
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(a) => vec![a],
+        OneOrMany::Many(a) => a,
+    })
+}
+
+// The field names a struct expects, as serde_derive hands them to
+// deserialize_struct; keeps the schema structs the only list of keys.
+fn fields_of<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+    impl<'de, 'a> Deserializer<'de> for Fields<'a> {
+        type Error = value::Error;
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, value::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, value::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("done"))
+        }
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+    let mut fields: &'static [&'static str] = &[];
+    T::deserialize(Fields(&mut fields)).ok();
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(a: u64) -> Option<Duration> {
+        Some(Duration::from_millis(a))
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("250ms"), ms(250));
+        assert_eq!(parse_duration("5s"), ms(5000));
+        assert_eq!(parse_duration(" 1m30s "), ms(90_000));
+        assert_eq!(parse_duration("2min"), ms(120_000));
+        assert_eq!(parse_duration("1h 1s"), ms(3_601_000));
+        assert_eq!(parse_duration("0s"), ms(0));
+    }
+    #[test]
+    fn rejects_bad_durations() {
+        for text in &["", "5", "s", "5x", "1.5s", "-1s", "99999999999999999h"] {
+            assert_eq!(parse_duration(text), None, "{:?}", text);
+        }
+    }
+    #[test]
+    fn reads_durations_as_millis_or_text() {
+        let text = "[svc]\nkill_timeout = 1500\nrestart_delay = \"2s\"\n";
+        let file: ServiceFile = toml::from_str(text).unwrap();
+        assert_eq!(file.svc.kill_timeout, Duration::from_millis(1500));
+        assert_eq!(file.svc.restart_delay, Duration::from_secs(2));
+        assert!(toml::from_str::<ServiceFile>("[svc]\nkill_timeout = \"soon\"\n").is_err());
+    }
+    #[test]
+    fn suggests_known_keys() {
+        let text = "[svc]\nexec = \"true\"\nkil_timeout = 5\nfrobnicate = 1\n";
+        let report = check(Kind::Service, Path::new("a.svc"), text);
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            report.warnings,
+            vec![
+                "a.svc:4: unknown key svc.frobnicate".to_string(),
+                "a.svc:3: unknown key svc.kil_timeout (did you mean kill_timeout?)".to_string(),
+            ]
+        );
+    }
+    #[test]
+    fn warns_about_unknown_tables() {
+        let report = check(Kind::Milestone, Path::new("milestone.toml"), "[milstone]\n");
+        assert_eq!(
+            report.warnings,
+            vec!["milestone.toml:1: unknown table [milstone]".to_string()]
+        );
+    }
+    #[test]
+    fn watchdog_needs_notify() {
+        let report = check(
+            Kind::Service,
+            Path::new("a.svc"),
+            "[svc]\nwatchdog_timeout = \"10s\"\n",
+        );
+        assert_eq!(report.errors.len(), 1);
+        let report = check(
+            Kind::Service,
+            Path::new("a.svc"),
+            "[svc]\nready = \"notify\"\nwatchdog_timeout = \"10s\"\n",
+        );
+        assert!(report.errors.is_empty());
+    }
+    #[test]
+    fn checks_cgroup_limits() {
+        let errors = |svc: &str| {
+            let text = format!("[svc]\n{}\n", svc);
+            check(Kind::Service, Path::new("a.svc"), &text).errors.len()
+        };
+        for svc in &[
+            "cpu_weight = 1",
+            "cpu_weight = \"10000\"",
+            "memory_max = 1048576",
+            "memory_max = \"512M\"",
+            "memory_max = \"2g\"",
+            "memory_max = \"max\"",
+        ] {
+            assert_eq!(errors(svc), 0, "{}", svc);
+        }
+        for svc in &[
+            "cpu_weight = 0",
+            "cpu_weight = 10001",
+            "cpu_weight = \"max\"",
+            "memory_max = -1",
+            "memory_max = \"\"",
+            "memory_max = \"M\"",
+            "memory_max = \"512MB\"",
+            "memory_max = \"lots\"",
+        ] {
+            assert_eq!(errors(svc), 1, "{}", svc);
+        }
+    }
+    #[test]
+    fn finds_env_list_cycles() {
+        let text = "[svc]\nenv_expand = true\nenv_list = { A = \"${B}\", B = \"${A}\" }\n";
+        let report = check(Kind::Service, Path::new("a.svc"), text);
+        assert_eq!(
+            report.errors,
+            vec!["a.svc: env_list has a reference cycle (A -> B -> A)".to_string()]
+        );
+    }
+    #[test]
+    fn skips_env_values_that_are_not_strings() {
+        let text = "[svc]\nenv_list = { A = \"a\", B = 2 }\n";
+        let report = check(Kind::Service, Path::new("a.svc"), text);
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            report.warnings,
+            vec!["a.svc:2: svc.env_list.B is not a string; skipping it".to_string()]
+        );
+        let text = "[svc]\nexec = \"true\"\n[svc.env_list]\nA = \"a\"\nB = true\n";
+        let report = check(Kind::Service, Path::new("a.svc"), text);
+        assert_eq!(
+            report.warnings,
+            vec!["a.svc:5: svc.env_list.B is not a string; skipping it".to_string()]
+        );
+        let file: ServiceFile = toml::from_str(text).unwrap();
+        assert_eq!(file.svc.env_list.keys().collect::<Vec<_>>(), vec!["A"]);
+    }
+    #[test]
+    fn lists_schema_keys() {
+        assert!(Kind::Service.keys().contains(&"kill_timeout"));
+        assert!(Kind::Milestone.keys().contains(&"on_failure"));
+        assert!(Kind::AirupConf.keys().contains(&"airup_home"));
+    }
+    #[test]
+    fn measures_edit_distance() {
+        assert_eq!(distance("kill_timeout", "kill_timeout"), 0);
+        assert_eq!(distance("kill_timeout", "kil_timeout"), 1);
+        assert_eq!(distance("exec", "pre_exec"), 4);
+    }
+}
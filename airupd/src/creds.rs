@@ -0,0 +1,216 @@
+use crate::User;
+use libc::{c_char, c_int, gid_t, group, passwd, uid_t};
+use serde::Deserialize;
+use std::{
+    ffi::{CStr, CString},
+    fmt::{Display, Formatter},
+    io, mem, ptr,
+};
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged, expecting = "a name or a numeric id")]
+pub enum Group {
+    Id(gid_t),
+    Name(String),
+}
+// Who a command runs as. Names are only looked up when the command is
+// started, because earlier stages may still be creating users.
+#[derive(Clone)]
+pub struct Identity {
+    pub user: Option<User>,
+    pub group: Option<Group>,
+    pub groups: Vec<Group>,
+}
+// An identity resolved to ids, ready to be taken on in a forked child.
+pub struct Creds {
+    uid: uid_t,
+    gid: gid_t,
+    groups: Vec<gid_t>,
+}
+
+impl Display for Group {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Group::Id(id) => write!(f, "{}", id),
+            Group::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{}", user)?,
+            None => write!(f, "{}", unsafe { libc::geteuid() })?,
+        }
+        if let Some(group) = &self.group {
+            write!(f, ":{}", group)?;
+        }
+        Ok(())
+    }
+}
+impl Identity {
+    pub fn new(user: Option<User>) -> Identity {
+        Identity {
+            user,
+            group: None,
+            groups: Vec::new(),
+        }
+    }
+    // None means the command simply inherits airupd's credentials.
+    pub fn resolve(&self) -> io::Result<Option<Creds>> {
+        if self.user.is_none() && self.group.is_none() && self.groups.is_empty() {
+            return Ok(None);
+        }
+        let not_found = |what: String| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", what))
+        };
+        let (uid, name, user_gid) = match &self.user {
+            Some(User::Name(name)) => {
+                let (uid, gid) =
+                    getpwnam(name).ok_or_else(|| not_found(format!("user {}", name)))?;
+                let name = CString::new(name.as_str()).ok();
+                (uid, name, Some(gid))
+            }
+            Some(User::Id(uid)) => match getpwuid(*uid) {
+                Some((name, gid)) => (*uid, Some(name), Some(gid)),
+                None => (*uid, None, None),
+            },
+            None => {
+                let uid = unsafe { libc::geteuid() };
+                (uid, None, Some(unsafe { libc::getegid() }))
+            }
+        };
+        let gid = match &self.group {
+            Some(group) => gid_of(group).ok_or_else(|| not_found(format!("group {}", group)))?,
+            None => user_gid.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("user {} has no passwd entry, so a group must be set", uid),
+                )
+            })?,
+        };
+        let mut groups = match &name {
+            Some(name) => grouplist(name, gid),
+            None => vec![gid],
+        };
+        for group in &self.groups {
+            let gid = gid_of(group).ok_or_else(|| not_found(format!("group {}", group)))?;
+            if !groups.contains(&gid) {
+                groups.push(gid);
+            }
+        }
+        // Without root we cannot change identity, which is fine as long as
+        // nothing needs to change.
+        if unsafe { libc::geteuid() } != 0
+            && uid == unsafe { libc::geteuid() }
+            && gid == unsafe { libc::getegid() }
+        {
+            return Ok(None);
+        }
+        Ok(Some(Creds { uid, gid, groups }))
+    }
+}
+impl Creds {
+    // Runs in the forked child, so it must stick to plain system calls.
+    pub fn apply(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn getpwnam(name: &str) -> Option<(uid_t, gid_t)> {
+    let cname = CString::new(name).ok()?;
+    let mut pwd: passwd = unsafe { mem::zeroed() };
+    let mut result: *mut passwd = ptr::null_mut();
+    let mut buf = vec![0 as c_char; 16384];
+    unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+    }
+    if result.is_null() {
+        return None;
+    }
+    Some((pwd.pw_uid, pwd.pw_gid))
+}
+fn getpwuid(uid: uid_t) -> Option<(CString, gid_t)> {
+    let mut pwd: passwd = unsafe { mem::zeroed() };
+    let mut result: *mut passwd = ptr::null_mut();
+    let mut buf = vec![0 as c_char; 16384];
+    unsafe {
+        libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+    }
+    if result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+    Some((name.to_owned(), pwd.pw_gid))
+}
+fn getgrnam(name: &str) -> Option<gid_t> {
+    let cname = CString::new(name).ok()?;
+    let mut grp: group = unsafe { mem::zeroed() };
+    let mut result: *mut group = ptr::null_mut();
+    let mut buf = vec![0 as c_char; 16384];
+    unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+    }
+    if result.is_null() {
+        return None;
+    }
+    Some(grp.gr_gid)
+}
+fn gid_of(group: &Group) -> Option<gid_t> {
+    match group {
+        Group::Id(id) => Some(*id),
+        Group::Name(name) => getgrnam(name),
+    }
+}
+fn grouplist(name: &CStr, gid: gid_t) -> Vec<gid_t> {
+    let mut n: c_int = 64;
+    let mut gids: Vec<gid_t> = Vec::new();
+    loop {
+        gids.resize(n as usize, gid);
+        let rv = unsafe { libc::getgrouplist(name.as_ptr(), gid, gids.as_mut_ptr(), &mut n) };
+        if rv >= 0 {
+            break;
+        }
+    }
+    gids.truncate(n as usize);
+    if !gids.contains(&gid) {
+        gids.push(gid);
+    }
+    gids
+}
+pub fn group_ids(names: &[String]) -> Vec<gid_t> {
+    let mut gids = Vec::new();
+    for name in names {
+        match getgrnam(name) {
+            Some(gid) => gids.push(gid),
+            None => warn!("Group {} in airup.conf does not exist!", name),
+        }
+    }
+    gids
+}
+pub fn groups_of(uid: uid_t, gid: gid_t) -> Vec<gid_t> {
+    match getpwuid(uid) {
+        Some((name, _)) => grouplist(&name, gid),
+        None => vec![gid],
+    }
+}
@@ -0,0 +1,285 @@
+use std::{collections::BTreeMap, env, fs, io};
+
+// The environment a service command runs with. It is assembled when the
+// command starts, so env_file may be written by an earlier pre_exec.
+#[derive(Clone, Default)]
+pub struct Environ {
+    // Start from nothing instead of airupd's own environment.
+    pub clear: bool,
+    // Expand ${VAR} in values of `vars`. VAR is the variable as the
+    // service gets it, whichever order env_list has the two in; a value
+    // naming its own variable gets the value from beneath env_list.
+    pub expand: bool,
+    // Files of KEY=VALUE lines; a leading '-' makes one optional.
+    pub files: Vec<String>,
+    pub vars: Vec<(String, String)>,
+}
+
+impl Environ {
+    pub fn new(env_list: &BTreeMap<String, String>) -> Environ {
+        Environ {
+            vars: env_list
+                .iter()
+                .map(|(key, val)| (key.clone(), val.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+    pub fn set(&mut self, key: &str, val: &str) {
+        self.vars.push((key.to_string(), val.to_string()));
+    }
+    // Files are read in order and env_list goes last, so it wins.
+    pub fn build(&self) -> io::Result<Vec<(String, String)>> {
+        let mut built: Vec<(String, String)> = Vec::new();
+        for file in &self.files {
+            let (path, optional) = match file.strip_prefix('-') {
+                Some(path) => (path, true),
+                None => (file.as_str(), false),
+            };
+            match fs::read_to_string(path) {
+                Ok(text) => {
+                    for (key, val) in parse_env_file(&text) {
+                        insert(&mut built, key, val);
+                    }
+                }
+                Err(e) if optional && e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("cannot read env_file {}: {}", path, e),
+                    ))
+                }
+            }
+        }
+        let vars = self
+            .expanded(&built)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (key, val) in vars {
+            insert(&mut built, key, val);
+        }
+        Ok(built)
+    }
+    // Cycles of references in env_list, found without reading env_file.
+    pub fn check(&self) -> Result<(), String> {
+        self.expanded(&[]).map(|_| ())
+    }
+    // `vars`, with ${VAR} expanded if asked to; `base` has the variables
+    // from env_file.
+    fn expanded(&self, base: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+        let mut vars: Vec<(String, String)> = Vec::new();
+        for (key, val) in &self.vars {
+            insert(&mut vars, key.clone(), val.clone());
+        }
+        if !self.expand {
+            return Ok(vars);
+        }
+        let mut done = vec![None; vars.len()];
+        for i in 0..vars.len() {
+            resolve(&vars, base, &mut done, &mut Vec::new(), i)?;
+        }
+        Ok(vars
+            .into_iter()
+            .zip(done)
+            .map(|((key, _), val)| (key, val.unwrap()))
+            .collect())
+    }
+}
+
+fn insert(vars: &mut Vec<(String, String)>, key: String, val: String) {
+    match vars.iter_mut().find(|(k, _)| *k == key) {
+        Some(var) => var.1 = val,
+        None => vars.push((key, val)),
+    }
+}
+// KEY=VALUE per line, as in /etc/default files. Blank lines, comments and
+// an `export ` prefix are skipped, and one level of quotes is removed.
+fn parse_env_file(text: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, val) = match line.split_once('=') {
+            Some(a) => a,
+            None => continue,
+        };
+        let val = val.trim();
+        let val = match (val.chars().next(), val.chars().last()) {
+            (Some(a), Some(b)) if val.len() >= 2 && a == b && (a == '"' || a == '\'') => {
+                &val[1..val.len() - 1]
+            }
+            _ => val,
+        };
+        vars.push((key.trim().to_string(), val.to_string()));
+    }
+    vars
+}
+// The value of vars[i] with its references expanded, resolving the
+// variables it names first. `path` holds those being resolved, so that
+// coming back to one of them is a cycle.
+fn resolve(
+    vars: &[(String, String)],
+    base: &[(String, String)],
+    done: &mut [Option<String>],
+    path: &mut Vec<usize>,
+    i: usize,
+) -> Result<String, String> {
+    if let Some(val) = &done[i] {
+        return Ok(val.clone());
+    }
+    if let Some(pos) = path.iter().position(|j| *j == i) {
+        let mut names: Vec<&str> = path[pos..].iter().map(|j| vars[*j].0.as_str()).collect();
+        names.push(&vars[i].0);
+        return Err(format!(
+            "env_list has a reference cycle ({})",
+            names.join(" -> ")
+        ));
+    }
+    path.push(i);
+    let (key, val) = &vars[i];
+    let val = expand(val, |name| {
+        match vars.iter().position(|(k, _)| k == name && k != key) {
+            Some(j) => resolve(vars, base, done, path, j),
+            None => Ok(beneath(name, base)),
+        }
+    })?;
+    path.pop();
+    done[i] = Some(val.clone());
+    Ok(val)
+}
+// The value of a variable env_list does not set: from env_file, or else
+// from airupd's environment. Unknown variables expand to nothing.
+fn beneath(name: &str, base: &[(String, String)]) -> String {
+    match base.iter().find(|(k, _)| k == name) {
+        Some((_, v)) => v.clone(),
+        None => env::var(name).unwrap_or_default(),
+    }
+}
+// Replace ${VAR} with what `lookup` gives for VAR.
+fn expand<F>(val: &str, mut lookup: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Result<String, String>,
+{
+    let mut out = String::new();
+    let mut rest = val;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(a) => start + a,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&lookup(&rest[start + 2..end])?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+    fn expand_list(
+        list: &[(&str, &str)],
+        base: &[(&str, &str)],
+    ) -> Result<Vec<(String, String)>, String> {
+        let environ = Environ {
+            expand: true,
+            vars: vars(list),
+            ..Default::default()
+        };
+        environ.expanded(&vars(base))
+    }
+
+    #[test]
+    fn parses_env_files() {
+        let text =
+            "# comment\n\nA=1\nexport B = two \nC=\"quoted value\"\nD='x'\nE=\"\nnot a variable\n";
+        assert_eq!(
+            parse_env_file(text),
+            vars(&[
+                ("A", "1"),
+                ("B", "two"),
+                ("C", "quoted value"),
+                ("D", "x"),
+                ("E", "\""),
+            ])
+        );
+    }
+    #[test]
+    fn expands_variables() {
+        let set = vars(&[("HOME", "/srv"), ("EMPTY", "")]);
+        let expand = |val| expand(val, |name| Ok(beneath(name, &set))).unwrap();
+        assert_eq!(expand("${HOME}/data"), "/srv/data");
+        assert_eq!(expand("a${EMPTY}b"), "ab");
+        assert_eq!(expand("${AIRUP_TEST_UNSET_VARIABLE}x"), "x");
+        assert_eq!(expand("${HOME"), "${HOME");
+        assert_eq!(expand("$HOME"), "$HOME");
+    }
+    #[test]
+    fn expands_from_airupd_environment() {
+        env::set_var("AIRUP_TEST_EXPAND", "outer");
+        assert_eq!(beneath("AIRUP_TEST_EXPAND", &[]), "outer");
+    }
+    #[test]
+    fn expands_whatever_the_order() {
+        assert_eq!(
+            expand_list(&[("A", "${B}-a"), ("B", "${C}-b"), ("C", "c")], &[]).unwrap(),
+            vars(&[("A", "c-b-a"), ("B", "c-b"), ("C", "c")])
+        );
+    }
+    #[test]
+    fn expands_own_name_from_beneath() {
+        assert_eq!(
+            expand_list(&[("PATH", "${PATH}:/opt/bin")], &[("PATH", "/bin")]).unwrap(),
+            vars(&[("PATH", "/bin:/opt/bin")])
+        );
+    }
+    #[test]
+    fn reports_reference_cycles() {
+        assert_eq!(
+            expand_list(&[("A", "${B}"), ("B", "x${C}"), ("C", "${A}")], &[]).unwrap_err(),
+            "env_list has a reference cycle (A -> B -> C -> A)"
+        );
+        let environ = Environ {
+            vars: vars(&[("A", "${B}"), ("B", "${A}")]),
+            ..Default::default()
+        };
+        assert!(environ.check().is_ok());
+    }
+    #[test]
+    fn env_list_wins_over_files() {
+        let path = env::temp_dir().join(format!("airup-environ-{}", std::process::id()));
+        fs::write(&path, "A=file\nB=file\n").unwrap();
+        let environ = Environ {
+            expand: true,
+            files: vec![
+                path.to_string_lossy().to_string(),
+                "-/nonexistent/env".to_string(),
+            ],
+            vars: vars(&[("B", "list"), ("C", "${A}-${B}")]),
+            ..Default::default()
+        };
+        let built = environ.build();
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            built.unwrap(),
+            vars(&[("A", "file"), ("B", "list"), ("C", "file-list")])
+        );
+    }
+    #[test]
+    fn missing_env_file_is_an_error() {
+        let environ = Environ {
+            files: vec!["/nonexistent/env".to_string()],
+            ..Default::default()
+        };
+        assert!(environ.build().is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{
+    creds::{group_ids, groups_of},
+    power, pre_shutdown,
+    supervisor::{svc_running_core, SupCmd, Supervisor, SvcStatus},
+    switch_milestone, Stage, BUILTIN_MILESTONES, CURRENT_STAGE, MILESTONE_BUSY, REACHED_MILESTONES,
+};
+use libairup::ipc::{
+    self, Error, ErrorCode, Method, PowerAction, Reply, Request, Response, SvcInfo,
+};
+use libc::{c_char, gid_t, uid_t};
+use nng::{
+    options::{transport::ipc::Permissions, Options, RecvFd},
+    ListenerOptions, Message, Protocol, Socket,
+};
+use std::{
+    env, fs,
+    os::unix::{fs::PermissionsExt, io::RawFd},
+    path::Path,
+    thread::Builder,
+};
+
+#[derive(PartialEq, Copy, Clone)]
+enum Access {
+    Status,
+    Control,
+    Power,
+}
+pub struct Server {
+    socket: Socket,
+    control_gids: Vec<gid_t>,
+    power_gids: Vec<gid_t>,
+}
+
+pub fn open(control_groups: &[String], power_groups: &[String]) -> Option<Server> {
+    let address = ipc::address();
+    // Anybody may connect and ask for status; what a peer may do beyond
+    // that is decided per request from its credentials.
+    if let Some(path) = address.strip_prefix("ipc://") {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir).ok();
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).ok();
+        }
+        fs::remove_file(path).ok();
+    }
+    let server = match Socket::new(Protocol::Rep0) {
+        Ok(a) => a,
+        Err(b) => {
+            error!("Failed to create NNG Socket({}): running in RO mode!", b);
+            return None;
+        }
+    };
+    let listener = ListenerOptions::new(&server, &address).and_then(|l| {
+        if address.starts_with("ipc://") {
+            l.set_opt::<Permissions>(0o666)?;
+        }
+        Ok(l)
+    });
+    if let Err(a) = listener.and_then(|l| l.start(false).map_err(|(_, e)| e)) {
+        error!(
+            "Failed to listen address {}({}): running in RO mode!",
+            address, a
+        );
+        return None;
+    }
+    info!("Starting communicating bridges...");
+    Some(Server {
+        socket: server,
+        control_gids: group_ids(control_groups),
+        power_gids: group_ids(power_groups),
+    })
+}
+impl Server {
+    pub fn recv_fd(&self) -> Option<RawFd> {
+        self.socket.get_opt::<RecvFd>().ok()
+    }
+    // Answer every request queued on the socket. Called from the supervisor
+    // loop whenever the receive descriptor becomes readable. Every request
+    // gets exactly one response, so a client can never be left waiting.
+    pub fn serve(&self, sup: &mut Supervisor) {
+        while let Ok(mut msg) = self.socket.try_recv() {
+            let resp = match Request::from_slice(msg.as_slice()) {
+                Ok(req) => {
+                    let result = match self.authorize(&mut msg, &req.method) {
+                        Ok(()) => handle(req.method, sup),
+                        Err(e) => Err(e),
+                    };
+                    Response::new(req.id, result)
+                }
+                Err(resp) => *resp,
+            };
+            self.socket.send(resp.to_vec().as_slice()).ok();
+        }
+    }
+    fn authorize(&self, msg: &mut Message, method: &Method) -> Result<(), Error> {
+        let access = match method {
+            Method::Status { .. } | Method::List | Method::Orphans | Method::Milestones => {
+                Access::Status
+            }
+            Method::Start { .. }
+            | Method::Stop { .. }
+            | Method::Restart { .. }
+            | Method::Reload
+            | Method::SwitchMilestone { .. }
+            | Method::Logs { .. } => Access::Control,
+            Method::Power { .. } => Access::Power,
+        };
+        if access == Access::Status {
+            return Ok(());
+        }
+        let granted = match peer_cred(msg) {
+            Some((0, _)) => true,
+            Some((uid, gid)) => {
+                let gids = match access {
+                    Access::Power => &self.power_gids,
+                    _ => &self.control_gids,
+                };
+                groups_of(uid, gid).iter().any(|g| gids.contains(g))
+            }
+            None => false,
+        };
+        if granted {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::PermissionDenied,
+                "permission denied: this request requires root",
+            ))
+        }
+    }
+}
+// Credentials of the process on the other end, taken by the kernel
+// (SO_PEERCRED) when it connected.
+fn peer_cred(msg: &mut Message) -> Option<(uid_t, gid_t)> {
+    let pipe = msg.pipe()?.nng_pipe();
+    let get = |opt: &[u8]| {
+        let mut val: u64 = 0;
+        let rv = unsafe {
+            nng::ffi::nng_pipe_getopt_uint64(pipe, opt.as_ptr() as *const c_char, &mut val)
+        };
+        if rv == 0 {
+            Some(val)
+        } else {
+            None
+        }
+    };
+    let uid = get(nng::ffi::NNG_OPT_IPC_PEER_UID)?;
+    let gid = get(nng::ffi::NNG_OPT_IPC_PEER_GID)?;
+    Some((uid as uid_t, gid as gid_t))
+}
+fn known(sup: &Supervisor, svc: &str) -> Result<(), Error> {
+    if sup.is_known(svc) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorCode::NoSuchService,
+            format!("service {} is not running", svc),
+        ))
+    }
+}
+fn ipc_status(status: SvcStatus) -> ipc::SvcStatus {
+    match status {
+        SvcStatus::Readying => ipc::SvcStatus::Readying,
+        SvcStatus::Running => ipc::SvcStatus::Running,
+        SvcStatus::Restarting => ipc::SvcStatus::Restarting,
+        SvcStatus::Stopping => ipc::SvcStatus::Stopping,
+        SvcStatus::Stopped | SvcStatus::Unmentioned => ipc::SvcStatus::Stopped,
+    }
+}
+fn handle(method: Method, sup: &mut Supervisor) -> Result<Reply, Error> {
+    match method {
+        Method::Start { svc } => {
+            if !sup.is_known(&svc) && sup.find_service(&svc).is_none() {
+                return Err(Error::new(
+                    ErrorCode::NoSuchService,
+                    format!("service {} does not exist", svc),
+                ));
+            }
+            sup.start_service(&svc)
+                .map_err(|e| Error::new(ErrorCode::Failed, e))?;
+            Ok(Reply::Done)
+        }
+        Method::Stop { svc } => {
+            known(sup, &svc)?;
+            sup.request(SupCmd::Stop(svc));
+            Ok(Reply::Done)
+        }
+        Method::Restart { svc } => {
+            known(sup, &svc)?;
+            sup.request(SupCmd::Restart(svc));
+            Ok(Reply::Done)
+        }
+        Method::Status { svc } => {
+            known(sup, &svc)?;
+            Ok(Reply::Status(SvcInfo {
+                status: ipc_status(svc_running_core(&svc)),
+                pid: sup.pid_of(&svc),
+                status_text: sup.status_text(&svc),
+                last_exit: sup.last_exit(&svc),
+                failure: sup.failure(&svc),
+                sandbox: sup.sandbox(&svc),
+                needs_restart: sup.needs_restart(&svc),
+                svc,
+            }))
+        }
+        Method::List => Ok(Reply::List {
+            services: sup
+                .list()
+                .into_iter()
+                .map(|(svc, pid)| SvcInfo {
+                    status: ipc_status(svc_running_core(&svc)),
+                    status_text: sup.status_text(&svc),
+                    last_exit: sup.last_exit(&svc),
+                    failure: sup.failure(&svc),
+                    sandbox: sup.sandbox(&svc),
+                    needs_restart: sup.needs_restart(&svc),
+                    svc,
+                    pid,
+                })
+                .collect(),
+        }),
+        Method::Logs { svc, since, lines } => {
+            let count = lines.unwrap_or(50) as usize;
+            match sup.logs(&svc, since, count) {
+                Some((lines, next)) => Ok(Reply::Logs { lines, next }),
+                None => Err(Error::new(
+                    ErrorCode::NoSuchService,
+                    format!("service {} is not running", svc),
+                )),
+            }
+        }
+        Method::Orphans => Ok(Reply::Orphans {
+            count: sup.orphans_reaped(),
+        }),
+        Method::Reload => {
+            let reloaded = sup.reload();
+            Ok(Reply::Reload {
+                added: reloaded.added,
+                changed: reloaded.changed,
+                needs_restart: reloaded.needs_restart,
+                failed: reloaded.failed,
+            })
+        }
+        Method::SwitchMilestone { milestone } => {
+            if *CURRENT_STAGE.read().unwrap() == Stage::Shutdown {
+                return Err(Error::new(ErrorCode::Failed, "the system is shutting down"));
+            }
+            let dir = Path::new(sup.airup_dir())
+                .join("milestones")
+                .join(&milestone);
+            let builtin = BUILTIN_MILESTONES.contains(&milestone.as_str());
+            if milestone.is_empty() || milestone.contains('/') || !(dir.is_dir() || builtin) {
+                return Err(Error::new(
+                    ErrorCode::NoSuchMilestone,
+                    format!("milestone {} does not exist", milestone),
+                ));
+            }
+            let mut busy = MILESTONE_BUSY.write().unwrap();
+            if *busy {
+                return Err(Error::new(
+                    ErrorCode::Failed,
+                    "a milestone is still being reached",
+                ));
+            }
+            sup.isolate(&milestone)
+                .map_err(|e| Error::new(ErrorCode::Failed, e))?;
+            switch_milestone(sup.airup_dir(), milestone)
+                .map_err(|e| Error::new(ErrorCode::Internal, e.to_string()))?;
+            *busy = true;
+            Ok(Reply::Done)
+        }
+        Method::Milestones => Ok(Reply::Milestones {
+            stage: CURRENT_STAGE.read().unwrap().to_string(),
+            target: env::var("AIRUP_TARGET_MILESTONE").unwrap_or_default(),
+            reached: REACHED_MILESTONES.read().unwrap().clone(),
+        }),
+        Method::Power { action } => {
+            if *CURRENT_STAGE.read().unwrap() == Stage::Shutdown {
+                return Err(Error::new(
+                    ErrorCode::Failed,
+                    "the system is already shutting down",
+                ));
+            }
+            if action == PowerAction::Kexec && !power::kexec_loaded() {
+                return Err(Error::new(
+                    ErrorCode::Failed,
+                    "no kernel is loaded for kexec",
+                ));
+            }
+            let ah = sup.airup_dir();
+            let action: fn() = match action {
+                PowerAction::Poweroff => power::poweroff,
+                PowerAction::Reboot => power::restart,
+                PowerAction::Halt => power::halt,
+                PowerAction::Kexec => power::kexec,
+                PowerAction::SoftReboot => power::soft_reboot,
+            };
+            // Shutting down waits for services, so it must not block the loop.
+            Builder::new()
+                .name("shutdown".to_string())
+                .spawn(move || {
+                    pre_shutdown(ah);
+                    action();
+                })
+                .map_err(|e| Error::new(ErrorCode::Internal, e.to_string()))?;
+            Ok(Reply::Done)
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::logs::timestamp;
+use ansi_term::Color::*;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    mem,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+// Where the boot log lives until `persist` moves it to its final place.
+const RUNTIME_BOOT_LOG: &str = "/run/airup/boot.log";
+
+#[derive(PartialEq, PartialOrd, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+pub struct JournalConfig {
+    pub level: Level,
+    // None picks colours only when stdout is a terminal.
+    pub color: Option<bool>,
+    pub boot_log: PathBuf,
+}
+// airupd's own diagnostics. Every message goes to the console and to the
+// boot log; while the boot log cannot be written yet, it goes to the
+// kernel log instead.
+struct Journal {
+    level: Level,
+    color: bool,
+    // None until prestart is done: a boot log under /run written before
+    // /run is mounted would end up hidden beneath the mount.
+    path: Option<PathBuf>,
+    file: Option<File>,
+    // Records already sent to the kernel log, kept to be replayed into the
+    // boot log once it has a path.
+    pending: Vec<String>,
+    persist_to: Option<PathBuf>,
+    kmsg: Option<File>,
+    kmsg_failed: bool,
+}
+
+static JOURNAL: Lazy<Mutex<Journal>> = Lazy::new(|| {
+    Mutex::new(Journal {
+        level: Level::Info,
+        color: unsafe { libc::isatty(1) } == 1,
+        path: None,
+        file: None,
+        pending: Vec::new(),
+        persist_to: None,
+        kmsg: None,
+        kmsg_failed: false,
+    })
+});
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::journal::log($crate::journal::Level::Error, format_args!($($arg)*))
+    };
+}
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::journal::log($crate::journal::Level::Warn, format_args!($($arg)*))
+    };
+}
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::journal::log($crate::journal::Level::Info, format_args!($($arg)*))
+    };
+}
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::journal::log($crate::journal::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+pub fn setup(conf: JournalConfig) {
+    let mut journal = JOURNAL.lock().unwrap_or_else(|e| e.into_inner());
+    journal.level = conf.level;
+    if let Some(color) = conf.color {
+        journal.color = color;
+    }
+    journal.persist_to = Some(conf.boot_log);
+}
+pub fn log(level: Level, args: fmt::Arguments) {
+    // Keep logging even if some thread panicked while holding the lock.
+    let mut journal = JOURNAL.lock().unwrap_or_else(|e| e.into_inner());
+    if level > journal.level {
+        return;
+    }
+    let msg = args.to_string();
+    journal.console(level, &msg);
+    journal.record(level, &msg);
+}
+// Start the boot log once prestart has mounted what it lives on, and move
+// it to its persistent place once that is writable.
+pub fn persist() {
+    let mut journal = JOURNAL.lock().unwrap_or_else(|e| e.into_inner());
+    if journal.path.is_none() {
+        journal.path = Some(PathBuf::from(RUNTIME_BOOT_LOG));
+        for line in mem::take(&mut journal.pending) {
+            journal.append(&line);
+        }
+    }
+    let path = journal.path.clone().unwrap();
+    let target = match &journal.persist_to {
+        Some(a) if *a != path => a.clone(),
+        _ => return,
+    };
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir).ok();
+    }
+    let mut file = match OpenOptions::new().create(true).append(true).open(&target) {
+        Ok(a) => a,
+        Err(_) => return,
+    };
+    if let Ok(text) = fs::read_to_string(&path) {
+        if file.write_all(text.as_bytes()).is_err() {
+            return;
+        }
+    }
+    fs::remove_file(&path).ok();
+    journal.path = Some(target);
+    journal.file = Some(file);
+}
+
+impl Journal {
+    fn console(&self, level: Level, msg: &str) {
+        let prefix = if self.color {
+            let color = match level {
+                Level::Error => Red,
+                Level::Warn => Yellow,
+                Level::Info => Green,
+                Level::Debug => Blue,
+            };
+            color.paint(" * ").to_string()
+        } else {
+            " * ".to_string()
+        };
+        match level {
+            Level::Error | Level::Warn => eprintln!("{}{}", prefix, msg),
+            _ => println!("{}{}", prefix, msg),
+        }
+    }
+    fn record(&mut self, level: Level, msg: &str) {
+        let name = match level {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+        // One logfmt record per line.
+        let line = format!(
+            "time={} level={} msg=\"{}\"\n",
+            timestamp(),
+            name,
+            msg.escape_default()
+        );
+        if self.path.is_none() {
+            self.kmsg(level, msg);
+            self.pending.push(line);
+            return;
+        }
+        if !self.append(&line) {
+            self.kmsg(level, msg);
+        }
+    }
+    fn append(&mut self, line: &str) -> bool {
+        let path = match &self.path {
+            Some(a) => a,
+            None => return false,
+        };
+        if self.file.is_none() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).ok();
+            }
+            self.file = OpenOptions::new().create(true).append(true).open(path).ok();
+        }
+        if let Some(file) = &mut self.file {
+            if file.write_all(line.as_bytes()).is_ok() {
+                return true;
+            }
+            self.file = None;
+        }
+        false
+    }
+    fn kmsg(&mut self, level: Level, msg: &str) {
+        if self.kmsg.is_none() && !self.kmsg_failed {
+            self.kmsg = OpenOptions::new().write(true).open("/dev/kmsg").ok();
+            self.kmsg_failed = self.kmsg.is_none();
+        }
+        let priority = match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        };
+        if let Some(kmsg) = &mut self.kmsg {
+            kmsg.write_all(format!("<{}>airupd: {}\n", priority, msg).as_bytes())
+                .ok();
+        }
+    }
+}
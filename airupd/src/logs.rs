@@ -0,0 +1,254 @@
+use libc::{c_void, F_DUPFD_CLOEXEC, F_GETFL, F_SETFL, O_CLOEXEC, O_NONBLOCK};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::{FromRawFd, RawFd},
+    path::PathBuf,
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Lines longer than this are split rather than buffered forever.
+const MAX_LINE: usize = 4096;
+
+#[derive(Clone)]
+pub struct LogConfig {
+    pub dir: PathBuf,
+    // Rotate a service's log once it grows beyond this many bytes,
+    // keeping `rotate` old files.
+    pub max_size: u64,
+    pub rotate: u32,
+    // How many lines each service keeps in memory for `airupctl logs`;
+    // 0 keeps none.
+    pub lines: usize,
+}
+// Output of one service. Every command run for the service writes into
+// the same pipe; airupd keeps the write end open, so it never sees EOF.
+pub struct ServiceLog {
+    id: String,
+    read_fd: RawFd,
+    write_fd: RawFd,
+    file: Option<File>,
+    size: u64,
+    warned: bool,
+    partial: Vec<u8>,
+    ring: VecDeque<String>,
+    // Sequence number of the line after the last one in `ring`.
+    next: u64,
+}
+
+impl ServiceLog {
+    pub fn open(id: &str) -> io::Result<ServiceLog> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Only our end is non-blocking; services get an ordinary pipe.
+        unsafe {
+            let flags = libc::fcntl(fds[0], F_GETFL);
+            libc::fcntl(fds[0], F_SETFL, flags | O_NONBLOCK);
+        }
+        Ok(ServiceLog {
+            id: id.to_string(),
+            read_fd: fds[0],
+            write_fd: fds[1],
+            file: None,
+            size: 0,
+            warned: false,
+            partial: Vec::new(),
+            ring: VecDeque::new(),
+            next: 0,
+        })
+    }
+    pub fn read_fd(&self) -> RawFd {
+        self.read_fd
+    }
+    // A fresh handle on the write end, for a child's stdout or stderr.
+    pub fn stdio(&self) -> io::Result<Stdio> {
+        let fd = unsafe { libc::fcntl(self.write_fd, F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { Stdio::from_raw_fd(fd) })
+    }
+    pub fn drain(&mut self, conf: &LogConfig) {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(self.read_fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            if n <= 0 {
+                break;
+            }
+            self.partial.extend_from_slice(&buf[..n as usize]);
+            while let Some(pos) = self.partial.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.partial.drain(..=pos).collect();
+                self.push(conf, &line[..line.len() - 1]);
+            }
+            if self.partial.len() >= MAX_LINE {
+                let line: Vec<u8> = self.partial.drain(..).collect();
+                self.push(conf, &line);
+            }
+        }
+    }
+    // Lines from sequence number `since` on (or the last `count` lines),
+    // and the number to ask from next time.
+    pub fn lines(&self, since: Option<u64>, count: usize) -> (Vec<String>, u64) {
+        let first = self.next - self.ring.len() as u64;
+        let skip = match since {
+            Some(since) => since.max(first) - first,
+            None => (self.ring.len().saturating_sub(count)) as u64,
+        };
+        let lines = self.ring.iter().skip(skip as usize).cloned().collect();
+        (lines, self.next)
+    }
+    fn push(&mut self, conf: &LogConfig, line: &[u8]) {
+        let line = format!("{} {}", timestamp(), String::from_utf8_lossy(line));
+        self.write(conf, &line);
+        while !self.ring.is_empty() && self.ring.len() >= conf.lines {
+            self.ring.pop_front();
+        }
+        if conf.lines > 0 {
+            self.ring.push_back(line);
+        }
+        self.next += 1;
+    }
+    fn write(&mut self, conf: &LogConfig, line: &str) {
+        if self.file.is_some() && self.size + line.len() as u64 + 1 > conf.max_size {
+            self.file = None;
+            self.rotate(conf);
+        }
+        // The log directory may only become writable later in boot, so
+        // keep trying until it does.
+        if self.file.is_none() {
+            match self.reopen(conf) {
+                Ok(()) => self.warned = false,
+                Err(e) => {
+                    if !self.warned {
+                        warn!(
+                            "Failed to open log file of service {}({}): keeping it in memory only!",
+                            self.id, e
+                        );
+                        self.warned = true;
+                    }
+                    return;
+                }
+            }
+        }
+        let file = self.file.as_mut().unwrap();
+        if writeln!(file, "{}", line).is_err() {
+            self.file = None;
+            return;
+        }
+        self.size += line.len() as u64 + 1;
+    }
+    fn path(&self, conf: &LogConfig, n: u32) -> PathBuf {
+        if n == 0 {
+            conf.dir.join(format!("{}.log", self.id))
+        } else {
+            conf.dir.join(format!("{}.log.{}", self.id, n))
+        }
+    }
+    fn rotate(&self, conf: &LogConfig) {
+        for n in (0..conf.rotate).rev() {
+            fs::rename(self.path(conf, n), self.path(conf, n + 1)).ok();
+        }
+        if conf.rotate == 0 {
+            fs::remove_file(self.path(conf, 0)).ok();
+        }
+    }
+    fn reopen(&mut self, conf: &LogConfig) -> io::Result<()> {
+        fs::create_dir_all(&conf.dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(conf, 0))?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+// UTC, as in 2020-11-03T12:01:02.345Z.
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Push lines "0", "1", ... into a log keeping `keep` of them.
+    fn log_of(keep: usize, count: usize) -> ServiceLog {
+        let dir = std::env::temp_dir().join(format!("airup-logs-{}-{}", std::process::id(), keep));
+        let conf = LogConfig {
+            dir: dir.clone(),
+            max_size: u64::MAX,
+            rotate: 0,
+            lines: keep,
+        };
+        let mut log = ServiceLog::open("test").unwrap();
+        for n in 0..count {
+            log.push(&conf, n.to_string().as_bytes());
+        }
+        fs::remove_dir_all(&dir).ok();
+        log
+    }
+    fn text(lines: (Vec<String>, u64)) -> (Vec<String>, u64) {
+        let (lines, next) = lines;
+        let lines = lines
+            .iter()
+            .map(|l| l.split_once(' ').unwrap().1.to_string())
+            .collect();
+        (lines, next)
+    }
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_the_last_lines() {
+        let log = log_of(3, 5);
+        assert_eq!(text(log.lines(None, 10)), (strings(&["2", "3", "4"]), 5));
+        assert_eq!(text(log.lines(None, 1)), (strings(&["4"]), 5));
+        assert_eq!(text(log.lines(None, 0)), (Vec::new(), 5));
+    }
+    #[test]
+    fn reads_on_from_a_sequence_number() {
+        let log = log_of(3, 5);
+        assert_eq!(text(log.lines(Some(3), 0)), (strings(&["3", "4"]), 5));
+        // Lines that fell out of the ring are gone.
+        assert_eq!(text(log.lines(Some(0), 0)), (strings(&["2", "3", "4"]), 5));
+        assert_eq!(text(log.lines(Some(5), 0)), (Vec::new(), 5));
+        assert_eq!(text(log.lines(Some(9), 0)), (Vec::new(), 5));
+    }
+    #[test]
+    fn keeps_nothing_when_disabled() {
+        let log = log_of(0, 4);
+        assert_eq!(text(log.lines(None, 10)), (Vec::new(), 4));
+        assert_eq!(text(log.lines(Some(2), 0)), (Vec::new(), 4));
+    }
+}
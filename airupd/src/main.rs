@@ -1,33 +1,52 @@
+#[macro_use]
+mod journal;
+mod attrs;
+mod cgroup;
+mod config;
+mod creds;
+mod environ;
+mod ipc;
+mod logs;
 mod power;
+mod sandbox;
+mod supervisor;
 
-use ansi_term::Color::*;
+use attrs::ProcAttrs;
+use cgroup::Cgroup;
+use config::{Color, OnFailure, Report};
+use creds::Identity;
+use environ::Environ;
+use journal::JournalConfig;
 use libc::{
-    c_int, getpid, kill, pid_t, sigfillset, sigprocmask, sigset_t, uid_t, waitpid, SIGKILL,
-    SIGTERM, SIG_BLOCK, WNOHANG,
+    c_int, getpid, ioctl, kill, pid_t, setsid, sigfillset, sigprocmask, sigset_t, uid_t, waitpid,
+    SIGHUP, SIGKILL, SIG_BLOCK, SIG_SETMASK, TIOCSCTTY,
 };
-use nng::{Protocol, Socket};
+use logs::{LogConfig, ServiceLog};
 use once_cell::sync::Lazy;
+use sandbox::Sandbox;
+use serde::{de, Deserialize, Deserializer};
 use std::{
-    cell::Cell,
     cmp::PartialEq,
-    collections::HashMap,
     convert::TryInto,
     env,
     fmt::{Display, Formatter},
     fs,
-    fs::ReadDir,
-    io, mem, panic,
+    fs::{OpenOptions, ReadDir},
+    io, mem,
+    os::unix::process::CommandExt,
+    panic,
     path::{Path, PathBuf},
-    process::{exit, Command},
-    sync::{
-        atomic::{AtomicU8, Ordering},
-        Arc, RwLock,
-    },
-    thread::{sleep, Builder},
+    process::{exit, Command, Stdio},
+    sync::{Mutex, RwLock},
+    thread::Builder,
     time,
 };
-use toml::{map::Map, Value};
+use supervisor::{
+    own_spawn, own_wait, sup_send, svc_wait_all_stopped, svcrun_all, svcrun_wait, SupCmd,
+};
 
+#[derive(Clone, Deserialize)]
+#[serde(untagged, expecting = "a name or a numeric id")]
 enum User {
     Id(uid_t),
     Name(String),
@@ -35,669 +54,117 @@ enum User {
 impl Display for User {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            User::Id(id) => write!(f, "{}", id.to_string()),
+            User::Id(id) => write!(f, "{}", id),
             User::Name(name) => write!(f, "{}", name),
         }
     }
 }
-#[derive(PartialEq, Copy, Clone)]
-enum SvcStatus {
-    Readying,
-    Running,
-    Restarting,
-    Stopped,
-    Unmentioned,
+// A command from a .svc file. A string runs through `sh -c`, an array is
+// executed directly.
+#[derive(Clone, Deserialize)]
+#[serde(untagged, expecting = "a command line or a non-empty argv array")]
+enum Cmdline {
+    Shell(String),
+    Argv(#[serde(deserialize_with = "argv")] Vec<String>),
+}
+impl Display for Cmdline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cmdline::Shell(cmd) => write!(f, "{}", cmd),
+            Cmdline::Argv(argv) => write!(f, "{}", argv.join(" ")),
+        }
+    }
+}
+impl Cmdline {
+    fn replace(&self, from: &str, to: &str) -> Cmdline {
+        match self {
+            Cmdline::Shell(cmd) => Cmdline::Shell(cmd.replace(from, to)),
+            Cmdline::Argv(argv) => {
+                Cmdline::Argv(argv.iter().map(|a| a.replace(from, to)).collect())
+            }
+        }
+    }
+}
+// What a service lends the commands it runs: the pipe capturing their
+// output, process attributes and, for the main process, its cgroup and
+// sandbox.
+#[derive(Default, Clone, Copy)]
+struct ExecCtx<'a> {
+    log: Option<&'a ServiceLog>,
+    attrs: Option<&'a ProcAttrs>,
+    cgroup: Option<&'a Cgroup>,
+    sandbox: Option<&'a Sandbox>,
 }
 #[derive(PartialEq)]
 enum Stage {
     PreStart,
     Milestones(String),
     Shutdown,
-    CtrlAltDel,
+}
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::PreStart => write!(f, "prestart"),
+            Stage::Milestones(prompt) => write!(f, "milestone {}", prompt),
+            Stage::Shutdown => write!(f, "shutdown"),
+        }
+    }
 }
 
-static AIRUP_GUARD_ENABLED: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
-static COMM_INIT: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
 static CURRENT_STAGE: Lazy<RwLock<Stage>> = Lazy::new(|| RwLock::new(Stage::PreStart));
+// Milestones reached since boot or the last switch, by directory name.
+static REACHED_MILESTONES: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
+// Whether a milestone is being reached, from boot on.
+static MILESTONE_BUSY: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(true));
+// Reachable even without a directory under airup_home/milestones.
+static BUILTIN_MILESTONES: &[&str] = &["rescue", "emergency"];
+// The console shell of the rescue or emergency milestone, while one runs.
+// Held while the target milestone changes, so that no shell is started
+// for a milestone being left.
+static RESCUE_SHELL: Lazy<Mutex<Option<pid_t>>> = Lazy::new(|| Mutex::new(None));
 static AIRUP_VERSION: &str = env!("CARGO_PKG_VERSION");
-static SVC_STATUS: Lazy<RwLock<HashMap<String, SvcStatus>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
-static DEFAULT_CFG: Lazy<HashMap<String, Value>> = Lazy::new(|| {
-    let mut a: HashMap<String, Value> = HashMap::new();
-    a.insert("airup/prestart_paral".to_string(), Value::Boolean(false));
-    a.insert(
-        "airup/osname".to_string(),
-        Value::String("Unknown OS".to_string()),
-    );
-    a.insert(
-        "airup/airup_home".to_string(),
-        Value::String("/etc/airup.d".to_string()),
-    );
-    let mut default_prestart_dir = a["airup/airup_home"].clone().as_str().unwrap().to_string();
-    default_prestart_dir.push_str("/prestart");
-    a.insert(
-        "airup/env_path".to_string(),
-        Value::String("DONT_SETUP".to_string()),
-    );
-    a.insert(
-        "internal/prestart_dir".to_string(),
-        Value::String(default_prestart_dir),
-    );
-    a.insert(
-        "milestone/description".to_string(),
-        Value::String("An airup milestone".to_string()),
-    );
-    a.insert("milestone/paral".to_string(), Value::Boolean(true));
-    a.insert("milestone/env_list".to_string(), Value::Table(Map::new()));
-    a.insert("milestone/dependencies".to_string(), Value::Array(vec![]));
-    a.insert(
-        "svc/description".to_string(),
-        Value::String("An airup service".to_string()),
-    );
-    a.insert("svc/env_list".to_string(), Value::Table(Map::new()));
-    a.insert("svc/take_io".to_string(), Value::Boolean(true));
-    a.insert("svc/dependencies".to_string(), Value::Array(Vec::new()));
-    a.insert("svc/stop_way".to_string(), Value::Integer(15));
-    a.insert("svc/cleanup_on_restart".to_string(), Value::Boolean(true));
-    a.insert("svc/retry_time".to_string(), Value::Integer(3));
-    a.insert("svc/kill_timeout".to_string(), Value::Integer(5000));
-    a
-});
-
 #[cfg(not(feature = "quickdbg"))]
 static AIRUP_CONF: &str = "/etc/airup.conf";
 
 #[cfg(feature = "quickdbg")]
 static AIRUP_CONF: &str = "debug/airup.conf";
+// How long shutdown waits for services to run their stop commands and
+// exit; their own kill_timeout normally ends it long before.
+const STOP_TIMEOUT: time::Duration = time::Duration::from_secs(90);
 
-fn pre_shutdown(ah: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("{}THIS COMPUTER IS SHUTTING DOWN...", Yellow.paint(" * "));
+fn pre_shutdown(ah: &str) {
+    warn!("THIS COMPUTER IS SHUTTING DOWN...");
     let mut ah = PathBuf::from(ah);
     ah.push("shutdown");
     let mut psh = ah.clone();
     psh.push("pre");
-    let files = airup_read_dir(fs::read_dir(psh)?);
-    for i in files {
-        system(&i);
-    }
-    println!("{}Executing pre-shutdown services...", Green.paint(" * "));
+    info!("Executing pre-shutdown services...");
+    shutdown_exec(&psh);
     *CURRENT_STAGE.write().unwrap() = Stage::Shutdown;
-    println!("{}Calling service supervisors to stop...", Green.paint(" * "));
+    info!("Calling service supervisors to stop...");
+    sup_send(SupCmd::StopAll);
     let mut msh = ah.clone();
     msh.push("cleanup");
-    let files = airup_read_dir(fs::read_dir(msh)?);
-    for i in files {
-        system(&i);
-    }
-    println!("{}Executing cleanup services...", Green.paint(" * "));
-    println!("{}Waiting for services stop...", Green.paint(" * "));
-    sleep(time::Duration::from_millis(3000));
-    Ok(())
-}
-// Start Service Supervisor
-fn svc_running_core(id: &str) -> SvcStatus {
-    *(*SVC_STATUS.read().unwrap())
-        .get(id)
-        .unwrap_or(&SvcStatus::Unmentioned)
-}
-fn svc_running_block(id: &str) -> bool {
-    if svc_running_core(id.clone()) == SvcStatus::Readying
-        || svc_running_core(id.clone()) == SvcStatus::Restarting
-    {
-        loop {
-            if svc_running_core(id) == SvcStatus::Running {
-                return true;
-            }
-        }
-    } else if svc_running_core(id.clone()) == SvcStatus::Running {
-        return true;
-    } else if svc_running_core(id.clone()) == SvcStatus::Stopped {
-        return true;
-    }
-    false
-}
-fn regsvc(id: &str, stat: SvcStatus) {
-    (*SVC_STATUS.write().unwrap()).insert(id.to_string(), stat);
-}
-fn regmsg(id: &str) -> Socket {
-    let base_dir = "inproc://airup/supervisors/";
-    let mut dir = String::from(base_dir);
-    dir.push_str(id.clone());
-    let skt = Socket::new(Protocol::Pair1).unwrap();
-    skt.listen(&dir).unwrap();
-    let telr = Socket::new(Protocol::Push0).unwrap();
-    telr.dial("inproc://airup/regsvc").unwrap();
-    let mut msg = String::from("up ");
-    msg.push_str(id);
-    telr.send(msg.as_bytes()).unwrap();
-    skt
-}
-fn delmsg(id: &str) {
-    let telr = Socket::new(Protocol::Push0).unwrap();
-    telr.dial("inproc://airup/regsvc").unwrap();
-    let mut msg = String::from("down ");
-    msg.push_str(id);
-    telr.send(msg.as_bytes()).unwrap();
-}
-fn delsvc(id: &str) {
-    (*SVC_STATUS.write().unwrap()).remove(id);
-}
-fn svcrun(airup_dir: &'static str, svctomlpath: &'static str) -> bool {
-    let svctoml = get_toml_of(svctomlpath);
-    if svctoml.is_none() {
-        eprintln!(
-            "{}Some problems happened when launching service {}.",
-            Red.paint(" * "),
-            svctomlpath
-        );
-        return false;
-    }
-    let svctoml = svctoml.unwrap();
-    let id = svcid_detect(svctomlpath.clone());
-    if svc_running_block(&id) {
-        return true;
-    }
-    let thrd = Builder::new().name(id.to_string());
-    let thrd = thrd.spawn(move || svc_supervisor_main(&id, airup_dir, svctoml));
-    if thrd.is_err() {
-        eprintln!("{}OS Error: Failed to create thread!", Red.paint(" * "));
-        return false;
-    }
-    true
-}
-fn svc_dep(airup_dir: &'static str, deps: Vec<String>) {
-    for dep in deps {
-        let mut i = String::from(&dep);
-        if i.starts_with("alias::") {
-            unimplemented!();
-        } else {
-            let mut e = PathBuf::from(airup_dir.clone());
-            e.push("svc");
-            i.push_str(".svc");
-            e.push(&i);
-            svcrun(
-                airup_dir.clone(),
-                Box::leak(e.to_string_lossy().to_string().into_boxed_str()),
-            );
-        }
-        loop {
-            if svc_running_block(&dep) {
-                break;
-            }
-        }
-    }
-}
-fn g_svc(set: &Value, vid: &str) -> Option<Value> {
-    let temp = tomlget(Some(set), "svc", vid.clone());
-    let default = Lazy::new(|| get_default_value("svc", vid));
-    if temp.is_none()
-        && (vid != "prompt"
-            && vid != "user"
-            && vid != "action_user"
-            && vid != "exec"
-            && vid != "pid_file"
-            && vid != "pre_exec"
-            && vid != "pre_stop"
-            && vid != "cleanup"
-            && vid != "pre_restart"
-            && vid != "ready_timeout"
-            && vid != "restart_way")
-    {
-        return Some(default.as_ref().unwrap().clone());
-    }
-    if temp.is_none()
-        && (vid == "prompt"
-            || vid == "user"
-            || vid == "action_user"
-            || vid == "exec"
-            || vid == "pid_file"
-            || vid == "pre_exec"
-            || vid == "pre_stop"
-            || vid == "cleanup"
-            || vid == "pre_restart"
-            || vid == "ready_timeout"
-            || vid == "restart_way")
-    {
-        return None;
-    }
-    let temp = temp.unwrap();
-    if (!temp.is_bool()) && (vid == "take_io") {
-        return Some(default.as_ref().unwrap().clone());
-    }
-    if (!temp.is_str()) && (vid == "description" || vid == "pre_exec" || vid == "exec") {
-        return Some(default.as_ref().unwrap().clone());
-    }
-    if (!temp.is_array()) && (vid == "dependencies") {
-        return Some(default.as_ref().unwrap().clone());
-    }
-    Some(temp)
-}
-fn envmaptostr(mp: &Map<String, Value>) -> String {
-    let keys: Vec<&String> = mp.keys().collect();
-    let mut r = String::new();
-    for key in keys.iter() {
-        let val = mp.get(key.clone()).unwrap();
-        if !val.is_str() {
-            continue;
-        }
-        let val = val.as_str().unwrap();
-        r.push_str(key);
-        r.push('=');
-        r.push('"');
-        r.push_str(val);
-        r.push('"');
-    }
-    r
-}
-fn get_user_by_value(val: Option<Value>) -> User {
-    if val.is_none() {
-        return User::Id(0);
-    }
-    let val = val.unwrap();
-    match val {
-        Value::Integer(a) => User::Id(a.try_into().unwrap()),
-        Value::String(b) => User::Name(b),
-        _ => User::Id(0),
-    }
-}
-fn svc_supervisor_main(id: &str, airup_dir: &'static str, svctoml: Value) {
-    regsvc(id.clone(), SvcStatus::Readying);
-    let channel = regmsg(id.clone());
-    // Ready some basic values.
-    let prompt = g_svc(&svctoml, "prompt").unwrap_or(Value::String(id.to_string()));
-    let prompt = prompt.as_str().unwrap_or(id.clone()).to_string();
-    let desc = g_svc(&svctoml, "description").unwrap();
-    let desc = desc.as_str().unwrap().to_string();
-    let env_map = g_svc(&svctoml, "env_list").unwrap();
-    let env_map = env_map.as_table().unwrap();
-    let sh_env = envmaptostr(env_map);
-    let user = g_svc(&svctoml, "user");
-    let action_user = g_svc(&svctoml, "action_user");
-    let user = get_user_by_value(user);
-    let action_user = get_user_by_value(action_user);
-    let take_io = g_svc(&svctoml, "take_io").unwrap().as_bool().unwrap();
-    let deps = g_svc(&svctoml, "dependencies").unwrap();
-    let deps = deps.as_array().unwrap();
-    let deps = vv_to_vs(deps.to_vec());
-    svc_dep(airup_dir, deps);
-    // Ready for exec
-    let pre_exec = g_svc(&svctoml, "pre_exec");
-    let exec = g_svc(&svctoml, "exec");
-    let pid_file = g_svc(&svctoml, "pid_file");
-    let exec = match exec {
-        Some(a) => Box::leak(a.as_str().unwrap().to_string().into_boxed_str()),
-        None => {
-            eprintln!(
-                "{}Failed to execute service {}: no 'exec' specified!",
-                Red.paint(" * "),
-                prompt
-            );
-            return;
-        }
-    };
-    // Ready for stop
-    let pre_stop = g_svc(&svctoml, "pre_stop");
-    // stop_way: string to exec a command, number to send signal
-    let stop_way = g_svc(&svctoml, "stop_way").unwrap();
-    let cleanup = g_svc(&svctoml, "cleanup");
-    // Ready for restart
-    let pre_restart = g_svc(&svctoml, "pre_restart");
-    let restart_way = g_svc(&svctoml, "restart_way").unwrap_or(stop_way.clone());
-    let cleanup_on_restart = g_svc(&svctoml, "cleanup_on_restart").unwrap();
-    let cleanup_on_restart = cleanup_on_restart.as_bool().unwrap();
-    // exception handling data.
-    let retry_time = g_svc(&svctoml, "retry_time").unwrap();
-    let retry_time = retry_time.as_integer().unwrap();
-    let ready_timeout = g_svc(&svctoml, "ready_timeout");
-    let kill_timeout = g_svc(&svctoml, "kill_timeout").unwrap();
-    let kill_timeout = kill_timeout.as_integer().unwrap();
-    // ready functions
-    let pid = Cell::new(0);
-    let id = String::from(id);
-    let kill_timer: Arc<AtomicU8> = Arc::new(AtomicU8::new(0));
-    let die_timer: Arc<AtomicU8> = Arc::new(AtomicU8::new(0));
-    let cleanup_now = || {
-        let cleanup = &cleanup;
-        if cleanup.is_some() {
-            let cleanup = cleanup.as_ref().unwrap();
-            if cleanup.is_str() {
-                let cleanup = cleanup.as_str().unwrap().to_string();
-                match asystem(&action_user, &cleanup, &sh_env) {
-                    Some(a) => {
-                        wait(a);
-                    }
-                    None => {
-                        eprintln!(
-                            "{}Failed to cleanup service {}: failed to run cleanup command \"{}\"!",
-                            Red.paint(" * "),
-                            &id,
-                            &cleanup
-                        );
-                        return;
-                    }
-                };
-            }
-        }
+    info!("Executing cleanup services...");
+    shutdown_exec(&msh);
+    info!("Waiting for services stop...");
+    if !svc_wait_all_stopped(STOP_TIMEOUT) {
+        error!("Some services did not stop in time!");
+    }
+}
+// Run the scripts of a shutdown stage one after another. A missing
+// directory has nothing to run.
+fn shutdown_exec(dir: &Path) {
+    let mut files = match fs::read_dir(dir) {
+        Ok(rd) => airup_read_dir(rd),
+        Err(_) => return,
     };
-    let full_stop = || {
-        if svc_running_core(&id) == SvcStatus::Stopped {
-            return;
-        }
-        let pre_stop = &pre_stop;
-        let stop_way = &stop_way;
-        if pre_stop.is_some() {
-            let pre_stop = pre_stop.as_ref().unwrap();
-            let pre_pid = asystem(&action_user, pre_stop.as_str().unwrap(), &sh_env);
-            if pre_pid.is_some() {
-                wait(pre_pid.unwrap());
-            }
-        }
-        let action_user = &action_user;
-        let env = &sh_env;
-        let pid = (&pid).get();
-        svc_stop(action_user, env, stop_way, pid.clone());
-        let kill_timeout = &kill_timeout;
-        timer(
-            time::Duration::from_millis(kill_timeout.clone().try_into().unwrap()),
-            kill_timer.clone(),
-        ).unwrap();
-        regsvc(&id, SvcStatus::Stopped);
-    };
-    let full_restart = || {
-        if svc_running_core(&id) == SvcStatus::Stopped {
-            return;
-        }
-        let pre_restart = &pre_restart;
-        let restart_way = &restart_way;
-        if pre_stop.is_some() {
-            let pre_restart = pre_restart.as_ref().unwrap();
-            let pre_pid = asystem(&action_user, pre_restart.as_str().unwrap(), &sh_env);
-            if pre_pid.is_some() {
-                wait(pre_pid.unwrap());
-            }
-        }
-        let pid = (&pid).get();
-        svc_stop(&action_user, &sh_env, restart_way, pid.clone());
-        let kill_timeout = &kill_timeout;
-        timer(
-            time::Duration::from_millis(kill_timeout.clone().try_into().unwrap()),
-            kill_timer.clone(),
-        ).unwrap();
-        regsvc(&id, SvcStatus::Restarting);
-    };
-    let full_exec = || {
-        let pre_exec = &pre_exec;
-        if pre_exec.is_some() {
-            let pre_exec = pre_exec.as_ref().unwrap();
-            let pre_pid = asystem(&action_user, pre_exec.as_str().unwrap(), &sh_env);
-            if pre_pid.is_some() {
-                wait(pre_pid.unwrap());
-            }
-        }
-        let pid = svc_exec(&user, &sh_env, &exec, take_io, &pid_file);
-        if pid.is_none() {
-            eprintln!(
-                "{}Failed to execute service {}!",
-                Red.paint(" * "),
-                Red.paint(prompt.clone())
-            );
-            return 0;
-        } else {
-            let ready_timeout = &ready_timeout;
-            if ready_timeout.is_some() {
-                let ready_timeout = ready_timeout.as_ref().unwrap();
-                let ready_timeout = ready_timeout.as_integer().unwrap();
-                let ready_timeout = match ready_timeout.try_into() {
-                    Ok(a) => a,
-                    Err(_) => {
-                        eprintln!(
-                            "{}{}: Timeout must be more than zero!",
-                            Red.paint(" * "),
-                            prompt.clone()
-                        );
-                        return 0;
-                    }
-                };
-                sleep(time::Duration::from_millis(ready_timeout));
-            }
-        }
-        let pid = pid.unwrap();
-        pid
-    };
-    // startup first
-    pid.set(full_exec());
-    if pid.get() == 0 {
-        eprintln!(
-            "{}Failed to start service {}!",
-            Red.paint(" * "),
-            Red.paint(prompt.clone())
-        );
-        return;
-    }
-    regsvc(&id, SvcStatus::Running);
-    println!(
-        "{}Starting service {}({})...",
-        Green.paint(" * "),
-        Green.paint(prompt.clone()),
-        Blue.paint(desc.clone())
-    );
-    // observe
-    let mut st_a = false;
-    let mut retry_count = 0;
-    let mut retry = true;
-    loop {
-        if *CURRENT_STAGE.read().unwrap() == Stage::Shutdown && !st_a {
-            st_a = true;
-            full_stop();
-        }
-        if svc_running_core(&id) == SvcStatus::Stopped {
-            if kill_timer.load(Ordering::Relaxed) != 0 {
-                if kill_timer.load(Ordering::Relaxed) == 2 {
-                    kill_timer.store(0, Ordering::Relaxed);
-                    if try_wait(pid.get()).is_none() {
-                        eprintln!(
-                            "{}Failed to stop service {} normally: Attempting to kill service!",
-                            Red.paint(" * "),
-                            &id
-                        );
-                        send_signal(pid.get(), SIGKILL);
-                    }
-                }
-                timer(time::Duration::from_secs(300), die_timer.clone()).unwrap();
-                cleanup_now();
-                pid.set(0);
-            } else if die_timer.load(Ordering::Relaxed) != 0 {
-                if die_timer.load(Ordering::Relaxed) == 2 {
-                    delsvc(&id);
-                    delmsg(&id);
-                    return;
-                }
-            }
-        }
-        let msg = channel.try_recv();
-        if msg.is_ok() {
-            let msg = msg.unwrap();
-            let msg = String::from_utf8_lossy(&msg);
-            if msg == "pid" {
-                match channel.send(pid.get().to_string().as_bytes()) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        continue;
-                    }
-                };
-            } else if msg == "down" {
-                retry_count = 0;
-                retry = false;
-                full_stop();
-            } else if msg == "up" {
-                retry_count = 0;
-                retry = true;
-                pid.set(full_exec());
-            } else if msg == "restart" {
-                retry_count = 0;
-                retry = true;
-                full_restart();
-            }
-        }
-        if svc_running_core(&id) == SvcStatus::Restarting && kill_timer.load(Ordering::Relaxed) != 0
-        {
-            if kill_timer.load(Ordering::Relaxed) == 2 {
-                kill_timer.store(0, Ordering::Relaxed);
-                if try_wait(pid.get()).is_none() {
-                    eprintln!(
-                        "{}Failed to stop service {} normally: Attempting to kill service!",
-                        Red.paint(" * "),
-                        &id
-                    );
-                    send_signal(pid.get(), SIGKILL);
-                }
-            }
-            if cleanup_on_restart {
-                cleanup_now();
-            }
-            let a = svc_exec(&user, &sh_env, &exec, take_io, &pid_file);
-            if a.is_some() {
-                let a = a.unwrap();
-                pid.set(a);
-            } else {
-                eprintln!(
-                    "{}Failed to execute service {}!",
-                    Red.paint(" * "),
-                    Red.paint(prompt.clone())
-                );
-                pid.set(0);
-            }
-        }
-        let t = try_wait(pid.get());
-        if t.is_some() {
-            let t = t.unwrap();
-            if t == 0 && retry && !(retry_count == retry_time) {
-                eprintln!(
-                    "{}Service {} stopped, but not returning an error. restarting...",
-                    Yellow.paint(" * "),
-                    Yellow.paint(prompt.clone())
-                );
-            } else if t != 0 && retry && !(retry_count == retry_time) {
-                eprintln!(
-                    "{}Service {} stopped unexpectedly! restarting...",
-                    Red.paint(" * "),
-                    Red.paint(prompt.clone())
-                );
-            }
-            if retry_count == retry_time && retry {
-                eprintln!(
-                    "{}Service {} restarted too many times!",
-                    Red.paint(" * "),
-                    Red.paint(prompt.clone())
-                );
-                regsvc(&id, SvcStatus::Stopped);
-                retry = false;
-            } else if retry_count != retry_time && retry {
-                retry_count += 1;
-                regsvc(&id, SvcStatus::Readying);
-                pid.set(full_exec());
-                if pid.get() == 0 {
-                    eprintln!(
-                        "{}Failed to restart service {}!",
-                        Red.paint(" * "),
-                        Red.paint(prompt.clone())
-                    );
-                    continue;
-                }
-                regsvc(&id, SvcStatus::Running);
-            }
-        }
-    }
-}
-fn svc_stop(action_user: &User, env: &str, stop_way: &Value, svc_pid: pid_t) -> bool {
-    match stop_way {
-        Value::String(s) => {
-            let p = asystem(
-                &action_user,
-                &s.replace("${PID}", &svc_pid.to_string()),
-                env.clone(),
-            );
-            match p {
-                Some(a) => {
-                    wait(a);
-                    return true;
-                }
-                None => {
-                    return false;
-                }
-            };
-        }
-        Value::Integer(i) => {
-            return send_signal(svc_pid, i.clone().try_into().unwrap_or(SIGTERM));
-        }
-        _ => {
-            return false;
-        }
-    };
-}
-fn svc_exec(
-    user: &User,
-    env: &str,
-    exec: &str,
-    take_io: bool,
-    pid_file: &Option<Value>,
-) -> Option<pid_t> {
-    let mut p = asystem(&user, exec.clone(), env.clone());
-    if pid_file.is_some() {
-        let pid_file = pid_file.as_ref().unwrap();
-        if !pid_file.is_str() {
-            eprintln!("{}Invalid value.", Red.paint(" * "));
-            return svc_exec(user, exec, env, take_io, &None);
-        }
-        let pid_file = pid_file.clone();
-        let pid_file = pid_file.as_str().unwrap();
-        let mut pid_str = String::new();
-        loop {
-            if Path::new(pid_file.clone()).exists() {
-                pid_str = fs::read_to_string(pid_file.clone()).unwrap();
-                break;
-            }
+    files.sort();
+    for i in files {
+        if let Some(pid) = own_spawn(true, || system(&i)) {
+            own_wait(pid);
         }
-        let pid = pid_str.parse::<pid_t>();
-        p = match pid {
-            Ok(a) => Some(a),
-            Err(_) => {
-                eprintln!("{}PID file format error!", Red.paint(" * "));
-                return svc_exec(user, exec, env, take_io, &None);
-            }
-        };
-    }
-    //Abort take_io until airpnv instead of airup_su
-    p
-}
-fn svcid_detect(svctomlpath: &str) -> String {
-    if fs::symlink_metadata(svctomlpath.clone())
-        .unwrap()
-        .file_type()
-        .is_symlink()
-    {
-        let rep = fs::read_link(svctomlpath.clone()).unwrap();
-        return svcid_detect(&rep.to_string_lossy());
-    } else {
-        let n = &Path::new(svctomlpath)
-            .file_name()
-            .unwrap()
-            .to_string_lossy();
-        n.replace(".svc", &String::new())
-    }
-}
-// End Service Supervisor
-fn timer(dur: time::Duration, m: Arc<AtomicU8>) -> Result<(), Box<dyn std::error::Error>> {
-    let thrd = Builder::new().name("timer".to_string());
-    {
-        let val = m.clone();
-        thrd.spawn(move || {
-            val.store(1, Ordering::Relaxed);
-            sleep(dur);
-            val.store(2, Ordering::Relaxed);
-        })?;
     }
-    Ok(())
 }
 fn send_signal(pid: pid_t, sig: c_int) -> bool {
     unsafe {
@@ -708,38 +175,25 @@ fn send_signal(pid: pid_t, sig: c_int) -> bool {
         false
     }
 }
-fn try_wait(pid: pid_t) -> Option<c_int> {
-    unsafe {
-        let mut status: c_int = 0;
-        let wait_status = waitpid(pid, &mut status as *mut c_int, WNOHANG);
-        if wait_status == 0 {
-            return None;
-        } else {
-            return Some(status);
-        }
-    }
-}
 fn wait(pid: pid_t) -> c_int {
     unsafe {
         let mut status: c_int = 0;
         waitpid(pid, &mut status as *mut c_int, 0);
-        return status;
+        status
     }
 }
 fn stage_prestart_exec(dir: &str, paral: bool) {
-    if !Path::new(dir.clone()).exists() {
-        println!(
-            "{}The specified prestart stage directory {} does not exist. Aborting...",
-            Red.paint(" * "),
+    if !Path::new(dir).exists() {
+        error!(
+            "The specified prestart stage directory {} does not exist. Aborting...",
             dir
         );
         return;
     }
-    let rd = fs::read_dir(dir.clone());
+    let rd = fs::read_dir(dir);
     if rd.is_err() {
-        println!(
-            "{}The specified prestart stage directory {} is not a directory. Aborting...",
-            Red.paint(" * "),
+        error!(
+            "The specified prestart stage directory {} is not a directory. Aborting...",
             dir
         );
         return;
@@ -754,7 +208,7 @@ fn stage_prestart_exec(dir: &str, paral: bool) {
         rda.push(i.as_ref().unwrap().path());
     }
     let rd = rda;
-    println!("{}Running PreStart Objects...", Green.paint(" * "));
+    info!("Running PreStart Objects...");
     for i in rd.iter() {
         let child = system(&i.to_string_lossy());
         if child.is_none() {
@@ -766,51 +220,98 @@ fn stage_prestart_exec(dir: &str, paral: bool) {
         }
     }
 }
+// Children must not inherit the signals airupd keeps blocked.
+fn child_setup(command: &mut Command) -> &mut Command {
+    unsafe {
+        command.pre_exec(|| {
+            let mut ss = new_sigset();
+            sigprocmask(SIG_SETMASK, &mut ss as *mut sigset_t, std::ptr::null_mut());
+            Ok(())
+        })
+    }
+}
 fn system(cmd: &str) -> Option<pid_t> {
-    let a = Command::new("sh").arg("-c").arg(cmd).spawn();
+    let a = child_setup(Command::new("sh").arg("-c").arg(cmd)).spawn();
     match a {
         Ok(b) => Some(b.id().try_into().unwrap()),
         Err(_) => None,
     }
 }
-fn asystem(user: &User, cmd: &str, env_list: &str) -> Option<pid_t> {
-    let mut command = String::from(env_list);
-    command.push_str(" exec ");
-    command.push_str(cmd);
-    #[cfg(feature = "no_airupsu")]
-    {
-        system(&command)
+fn asystem(ident: &Identity, cmd: &Cmdline, env: &Environ, ctx: ExecCtx) -> Option<pid_t> {
+    // Look the names up before forking; the child only makes system calls.
+    let creds = match ident.resolve() {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to run \"{}\" as {}: {}!", cmd, ident, e);
+            return None;
+        }
+    };
+    let envs = match env.build() {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to run \"{}\": {}!", cmd, e);
+            return None;
+        }
+    };
+    let mut command = match cmd {
+        Cmdline::Shell(cmd) => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(format!("exec {}", cmd));
+            command
+        }
+        Cmdline::Argv(argv) => {
+            let mut command = Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command
+        }
+    };
+    if env.clear {
+        command.env_clear();
+    }
+    command.envs(envs);
+    if let Some(log) = ctx.log {
+        match (log.stdio(), log.stdio()) {
+            (Ok(out), Ok(err)) => {
+                command.stdin(Stdio::null()).stdout(out).stderr(err);
+            }
+            (Err(e), _) | (_, Err(e)) => warn!("Failed to capture output of \"{}\"({})!", cmd, e),
+        }
     }
-    #[cfg(not(feature = "no_airupsu"))]
-    {
-        let mode: &str = match user {
-            User::Id(_) => "--uid",
-            User::Name(_) => "-u",
-        };
-        let user = user.to_string();
-        let a = Command::new("airup_su")
-            .arg(mode)
-            .arg(user)
-            .arg("-c")
-            .arg(command)
-            .spawn();
-        match a {
-            Ok(b) => Some(b.id().try_into().unwrap()),
-            Err(_) => None,
+    child_setup(&mut command);
+    // Join the cgroup while still privileged enough to.
+    if let Some(cgroup) = ctx.cgroup.cloned() {
+        unsafe {
+            command.pre_exec(move || cgroup.join());
         }
     }
-}
-fn get_toml_of(path: &str) -> Option<Value> {
-    let fontext = fs::read_to_string(path);
-    if fontext.is_err() {
-        return None;
+    // Namespaces and mounts first: a chroot in attrs would hide them.
+    if let Some(sandbox) = ctx.sandbox.cloned() {
+        unsafe {
+            command.pre_exec(move || sandbox.enter());
+        }
     }
-    let fontext = fontext.unwrap();
-    let rslt = fontext.parse::<Value>();
-    if rslt.is_err() {
-        return None;
+    if let Some(attrs) = ctx.attrs.cloned() {
+        unsafe {
+            command.pre_exec(move || attrs.apply());
+        }
+    }
+    if let Some(creds) = creds {
+        unsafe {
+            command.pre_exec(move || creds.apply());
+        }
+    }
+    if let Some(sandbox) = ctx.sandbox.cloned() {
+        unsafe {
+            command.pre_exec(move || sandbox.lock());
+        }
+    }
+    match command.spawn() {
+        Ok(b) => Some(b.id().try_into().unwrap()),
+        Err(e) => {
+            error!("Failed to run \"{}\": {}!", cmd, e);
+            None
+        }
     }
-    Some(rslt.unwrap())
 }
 fn sigfillset_s(sset: &mut sigset_t) {
     unsafe {
@@ -834,95 +335,48 @@ fn disable_signals() {
     sigfillset_s(&mut ssa);
     sigprocmask_s(SIG_BLOCK, &mut ssa, &mut ssb);
 }
-fn get_default_value(ns: &str, vid: &str) -> Option<Value> {
-    let mut full_name = String::from(ns);
-    full_name.push('/');
-    full_name.push_str(vid);
-    let rslt = (*DEFAULT_CFG).get(&full_name);
-    if rslt.is_none() {
-        return None;
-    }
-    Some(rslt.unwrap().clone())
-}
-fn tomlget(set: Option<&Value>, ns: &str, vid: &str) -> Option<Value> {
-    if set.is_none() {
-        return get_default_value(ns, vid);
-    }
-    let rslt = set.unwrap();
-    let rslt = rslt.get(ns.clone());
-    if rslt.is_none() {
-        return get_default_value(ns, vid);
-    }
-    let rslt = rslt.unwrap();
-    let rslt = rslt.get(vid.clone());
-    if rslt.is_none() {
-        return get_default_value(ns, vid);
-    }
-    Some(rslt.unwrap().clone())
-}
-fn g_airupconf(set: Option<&Value>, vid: &str) -> Value {
-    let temp = tomlget(set, "airup", vid.clone());
-    let default = Lazy::new(|| get_default_value("airup", vid).unwrap());
-    if temp.is_none() {
-        return default.clone();
-    }
-    let temp = temp.unwrap();
-    if (!temp.is_str()) && (vid == "osname" || vid == "airup_home" || vid == "env_path") {
-        return default.clone();
-    } else if (!temp.is_bool()) && vid == "prestart_paral" {
-        return default.clone();
-    }
-    let rslt = temp;
-    rslt
-}
-fn g_milestonetoml(set: Option<&Value>, vid: &str) -> Option<Value> {
-    let temp = tomlget(set, "milestone", vid.clone());
-    let default = Lazy::new(|| get_default_value("milestone", vid));
-    if temp.is_none() && vid != "prompt" && vid != "pre_exec" {
-        return Some(default.as_ref().unwrap().clone());
-    }
-    if temp.is_none() && (vid == "prompt" || vid == "pre_exec") {
-        return None;
-    }
-    let temp = temp.unwrap();
-    if (!temp.is_str()) && (vid == "prompt" || vid == "description" || vid == "pre_exec") {
-        return Some(default.as_ref().unwrap().clone());
-    } else if (!temp.is_bool()) && vid == "paral" {
-        return Some(default.as_ref().unwrap().clone());
-    } else if (!temp.is_array()) && vid == "dependencies" {
-        return Some(default.as_ref().unwrap().clone());
-    }
-    Some(temp)
-}
 fn pid_detect() {
     #[cfg(not(feature = "quickdbg"))]
     {
         if getpid_s() != 1 {
-            println!("{}This program can only run as PID 1 as long as feature 'quickdbg' is not enabled.", Red.paint(" * "));
+            error!(
+                "This program can only run as PID 1 as long as feature 'quickdbg' is not enabled."
+            );
             exit(-1);
         }
         disable_signals();
     }
 }
-fn pathsetup(val: &str) {
-    if val == "DONT_SETUP" {
-        return;
-    } else {
-        env::set_var("PATH", val);
-    }
-}
+// The kernel passes init only the words of its command line it does not
+// know itself, and of those, puts key=value words in the environment and
+// drops words with a '.'. /proc/cmdline has them all, if it is mounted.
 fn get_milestone() -> String {
-    let args: Vec<String> = env::args().collect();
-    let mut milestone: String = String::new();
-    for i in args.iter() {
-        if i.starts_with("milestone=") {
-            milestone = i.to_string()[10..].to_string();
+    if let Ok(cmdline) = fs::read_to_string("/proc/cmdline") {
+        return milestone_of(&cmdline);
+    }
+    let mut words: Vec<String> = env::args().skip(1).collect();
+    if let Ok(milestone) = env::var("milestone") {
+        words.push(format!("milestone={}", milestone));
+    }
+    milestone_of(&words.join(" "))
+}
+fn milestone_of(cmdline: &str) -> String {
+    let words: Vec<&str> = cmdline.split_whitespace().collect();
+    let mut milestone = "default";
+    for i in words.iter() {
+        if let Some(name) = i.strip_prefix("milestone=") {
+            if !name.is_empty() {
+                milestone = name;
+            }
         }
     }
-    if milestone == String::new() {
-        milestone = "default".to_string();
+    // These win over milestone=, whatever the order.
+    for mode in BUILTIN_MILESTONES {
+        if words.contains(&format!("airup.{}", mode).as_str()) {
+            milestone = mode;
+        }
     }
-    milestone
+    milestone.to_string()
 }
 fn set_airenv(ms: &str, ad: &str, par: bool) {
     env::set_var("AIRUP_TARGET_MILESTONE", ms);
@@ -931,61 +385,36 @@ fn set_airenv(ms: &str, ad: &str, par: bool) {
 }
 fn set_panic() {
     panic::set_hook(Box::new(|panic_info| {
-        eprintln!("{}{}", Red.paint(" * "), panic_info);
+        error!("{}", panic_info);
     }));
 }
-// The following function is made for "dependencies" toml object resolving.
-fn vv_to_vs(vv: Vec<Value>) -> Vec<String> {
-    let mut vs = Vec::new();
-    for i in vv {
-        if !i.is_str() {
-            continue;
-        }
-        let i = i.as_str().unwrap();
-        vs.push(i.to_string());
-    }
-    vs
-}
-fn toml_env_setup(env_list: Value) {
-    let env_list = env_list.as_table();
-    let env_list = match env_list {
-        Some(a) => a,
-        None => {
-            return;
-        }
-    };
-    let keys: Vec<&String> = env_list.keys().collect();
-    for key in keys {
-        let value = env_list.get(key).unwrap();
-        if !value.is_str() {
-            continue;
-        }
-        let value = value.as_str().unwrap();
-        env::set_var(key, value);
+fn argv<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    let argv = Vec::<String>::deserialize(d)?;
+    if argv.is_empty() {
+        return Err(de::Error::invalid_length(0, &"a program to run"));
     }
+    Ok(argv)
 }
-fn milestone_dep(ad: &str, mdir: &str, deps: Vec<String>) {
+fn milestone_dep(ad: &str, mdir: &str, deps: &[String]) {
     for i in deps.iter() {
-        let mut dir = PathBuf::from(mdir.clone());
+        let mut dir = PathBuf::from(mdir);
         dir.push(i);
         milestone_exec(ad, &dir.to_string_lossy());
     }
 }
-fn milestone_exec(ad: &str, dir: &str) {
+fn milestone_exec(ad: &str, dir: &str) -> bool {
     // Judge if the milestone exists
-    if !Path::new(dir.clone()).exists() {
-        println!(
-            "{}The specified milestone {} does not exist.",
-            Red.paint(" * "),
-            Red.paint(dir.clone())
-        );
-        return;
+    if !Path::new(dir).exists() {
+        error!("The specified milestone {} does not exist.", dir);
+        return false;
     }
     // Find milestone.toml
-    let mut mtpath = PathBuf::from(dir.clone());
+    let mut mtpath = PathBuf::from(dir);
     mtpath.push("milestone.toml");
     // Ready data
-    let milestone_toml = get_toml_of(&mtpath.to_string_lossy());
+    let mut report = Report::default();
+    let milestone = config::read_milestone(&mtpath, &mut report);
+    report.log();
     let invalid = std::ffi::OsString::from("invalid");
     let default_prompt = mtpath
         .parent()
@@ -993,58 +422,129 @@ fn milestone_exec(ad: &str, dir: &str) {
         .file_name()
         .unwrap_or(&invalid)
         .to_string_lossy();
-    let prompt = g_milestonetoml(milestone_toml.as_ref(), "prompt")
-        .unwrap_or(Value::String(default_prompt.to_string()));
-    let prompt = prompt.as_str().unwrap();
-    let description = g_milestonetoml(milestone_toml.as_ref(), "description").unwrap();
-    let description = description.as_str().unwrap();
-    let paral = g_milestonetoml(milestone_toml.as_ref(), "paral")
-        .unwrap()
-        .as_bool()
-        .unwrap();
-    let env = g_milestonetoml(milestone_toml.as_ref(), "env_list").unwrap();
-    // pre_exec may be Option::None.
-    let pre_exec = g_milestonetoml(milestone_toml.as_ref(), "pre_exec");
-    let dependencies = g_milestonetoml(milestone_toml.as_ref(), "dependencies").unwrap();
-    let dependencies = dependencies.as_array().unwrap();
-    let _files = fs::read_dir(dir.clone());
+    let prompt = milestone
+        .prompt
+        .unwrap_or_else(|| default_prompt.to_string());
+    let description = milestone.description;
+    let abort = milestone.on_failure == OnFailure::Abort;
+    let _files = fs::read_dir(dir);
     if _files.is_err() {
-        println!(
-            "{}The specified milestone path {} is not a directory.",
-            Red.paint(" * "),
-            Red.paint(dir.clone())
-        );
-        return;
+        error!("The specified milestone path {} is not a directory.", dir);
+        return false;
     }
     let files = airup_read_dir(_files.unwrap());
     // Action
-    toml_env_setup(env);
-    println!(
-        "{}Reaching milestone {}({})...",
-        Green.paint(" * "),
-        Green.paint(prompt.clone()),
-        Purple.paint(description)
-    );
-    (*CURRENT_STAGE.write().unwrap()) = Stage::Milestones(prompt.to_string());
-    match pre_exec {
-        Some(a) => {
-            if !a.is_str() {
-                ()
-            }
-            let a = a.as_str().unwrap();
-            system(a);
-        }
-        None => (),
-    };
+    for (key, value) in &milestone.env_list {
+        env::set_var(key, value);
+    }
+    info!("Reaching milestone {}({})...", prompt, description);
+    if let Some(a) = &milestone.pre_exec {
+        own_spawn(false, || system(a));
+    }
     milestone_dep(
-        ad.clone(),
-        &Path::new(dir.clone()).parent().unwrap().to_string_lossy(),
-        vv_to_vs(dependencies.clone()),
+        ad,
+        &Path::new(dir).parent().unwrap().to_string_lossy(),
+        &milestone.dependencies,
     );
-    if paral {
-        milestone_svcexec(Box::leak(ad.to_string().into_boxed_str()), files);
+    // Set after the dependencies, which set their own.
+    let mut stage = CURRENT_STAGE.write().unwrap();
+    if *stage == Stage::Shutdown {
+        return true;
+    }
+    *stage = Stage::Milestones(prompt.to_string());
+    drop(stage);
+    let ran = if milestone.paral {
+        milestone_svcexec(files)
     } else {
-        unimplemented!();
+        milestone_svcexec_serial(files, &milestone.order, abort)
+    };
+    match ran {
+        Some(true) => milestone_reached(&default_prompt),
+        Some(false) => warn!("Not all services of milestone {} are running.", prompt),
+        None => {
+            error!("Failed to reach milestone {}!", prompt);
+            return false;
+        }
+    }
+    true
+}
+fn milestone_reached(milestone: &str) {
+    let mut milestones = REACHED_MILESTONES.write().unwrap();
+    if !milestones.iter().any(|m| m == milestone) {
+        milestones.push(milestone.to_string());
+    }
+}
+// The built-in milestones: nothing but a root shell on the console, run
+// again whenever it exits until another milestone is switched to.
+fn rescue_exec(milestone: &str) -> bool {
+    let shell = config::read_airup_conf(Path::new(AIRUP_CONF), &mut Report::default()).rescue_shell;
+    let mut stage = CURRENT_STAGE.write().unwrap();
+    if *stage == Stage::Shutdown {
+        return true;
+    }
+    *stage = Stage::Milestones(milestone.to_string());
+    drop(stage);
+    warn!("Entering {} mode...", milestone);
+    let pid = match own_spawn(true, || console_shell(&shell)) {
+        Some(a) => a,
+        None => return false,
+    };
+    *RESCUE_SHELL.lock().unwrap() = Some(pid);
+    milestone_reached(milestone);
+    let milestone = milestone.to_string();
+    let spawned = Builder::new().name(milestone.clone()).spawn(move || {
+        let mut pid = pid;
+        loop {
+            own_wait(pid);
+            let mut running = RESCUE_SHELL.lock().unwrap();
+            *running = None;
+            let target = env::var("AIRUP_TARGET_MILESTONE").unwrap_or_default();
+            if target != milestone || *CURRENT_STAGE.read().unwrap() == Stage::Shutdown {
+                return;
+            }
+            pid = match own_spawn(true, || console_shell(&shell)) {
+                Some(a) => a,
+                None => return,
+            };
+            *running = Some(pid);
+        }
+    });
+    spawned.is_ok()
+}
+// Leaving rescue or emergency mode takes its root shell away from the
+// console: the session is hung up, and the shell itself killed.
+fn rescue_end(running: &mut Option<pid_t>) {
+    if let Some(pid) = running.take() {
+        send_signal(-pid, SIGHUP);
+        send_signal(pid, SIGKILL);
+    }
+}
+// Run a shell on the console, leading a session of its own so that it
+// has the console as its controlling terminal.
+fn console_shell(shell: &str) -> Option<pid_t> {
+    let mut command = Command::new(shell);
+    if let Ok(console) = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/console")
+    {
+        if let (Ok(a), Ok(b)) = (console.try_clone(), console.try_clone()) {
+            command.stdin(a).stdout(b).stderr(console);
+        }
+    }
+    unsafe {
+        command.pre_exec(|| {
+            setsid();
+            ioctl(0, TIOCSCTTY, 1);
+            Ok(())
+        });
+    }
+    match child_setup(&mut command).spawn() {
+        Ok(a) => Some(a.id().try_into().unwrap()),
+        Err(e) => {
+            error!("Failed to run shell {}: {}!", shell, e);
+            None
+        }
     }
 }
 fn airup_read_dir(_files: ReadDir) -> Vec<String> {
@@ -1055,277 +555,193 @@ fn airup_read_dir(_files: ReadDir) -> Vec<String> {
     }
     files
 }
-fn milestone_svcexec(ad: &'static str, files: Vec<String>) {
-    for i in files {
-        if !i.ends_with(".svc") {
-            continue;
+fn milestone_svcexec(files: Vec<String>) -> Option<bool> {
+    let files: Vec<String> = files.into_iter().filter(|i| i.ends_with(".svc")).collect();
+    svcrun_all(&files)
+}
+// Start the services one at a time: first those named in `order`, then the
+// rest by file name. Returns None if the milestone was aborted, else
+// whether every service came to run.
+fn milestone_svcexec_serial(files: Vec<String>, order: &[String], abort: bool) -> Option<bool> {
+    let mut files: Vec<String> = files.into_iter().filter(|i| i.ends_with(".svc")).collect();
+    files.sort();
+    let mut queue: Vec<String> = Vec::new();
+    for id in order {
+        match files
+            .iter()
+            .position(|i| &supervisor::svcid_detect(i) == id)
+        {
+            Some(pos) => queue.push(files.remove(pos)),
+            None => warn!("Service {} in the milestone order does not exist!", id),
         }
-        svcrun(ad.clone(), Box::leak(i.into_boxed_str()));
     }
+    queue.append(&mut files);
+    let mut ran = true;
+    for i in queue {
+        if !svcrun_wait(&i) {
+            if abort {
+                return None;
+            }
+            ran = false;
+        }
+    }
+    Some(ran)
 }
-fn stage_milestone_start(ad: &str, dir: &str, milestone: &str) {
+fn stage_milestone_start(ad: &str, dir: &str, milestone: &str) -> bool {
     let mut dir = PathBuf::from(dir);
     dir.push(milestone);
-    milestone_exec(ad, &dir.to_string_lossy());
+    if !dir.exists() && BUILTIN_MILESTONES.contains(&milestone) {
+        return rescue_exec(milestone);
+    }
+    milestone_exec(ad, &dir.to_string_lossy())
 }
-fn enable_rw(ah: &str) {
-    let address = "tcp://127.0.0.1:61257";
-    let server = Socket::new(Protocol::Rep0);
-    let server = match server {
-        Ok(a) => a,
-        Err(b) => {
-            eprintln!(
-                "{}Failed to create NNG Socket({}): running in RO mode!",
-                Red.paint(" * "),
-                b
-            );
-            return;
-        }
-    };
-    let c = server.listen(address.clone());
-    match c {
-        Ok(_) => (),
-        Err(a) => {
-            eprintln!(
-                "{}Failed to listen address {}({}): running in RO mode!",
-                Red.paint(" * "),
-                address,
-                a
-            );
-            return;
+// Boot to the target milestone, else to the fallback one, else to an
+// emergency shell.
+fn stage_milestones(ad: &str, dir: &str, milestone: &str, fallback: &str) {
+    let mut tried: Vec<&str> = Vec::new();
+    for m in [milestone, fallback, "emergency"].iter() {
+        if tried.contains(m) {
+            continue;
         }
-    };
-    let mut sups: HashMap<String, Socket> = HashMap::new();
-    let supls = Socket::new(Protocol::Pull0);
-    let supls = match supls {
-        Ok(a) => a,
-        Err(b) => {
-            eprintln!(
-                "{}Failed to create NNG Socket({}): running in RO mode!",
-                Red.paint(" * "),
-                b
-            );
-            return;
+        if !tried.is_empty() {
+            warn!("Falling back to milestone {}...", m);
+            env::set_var("AIRUP_TARGET_MILESTONE", m);
+            REACHED_MILESTONES.write().unwrap().clear();
         }
-    };
-    let c = supls.listen("inproc://airup/regsvc");
-    match c {
-        Ok(_) => (),
-        Err(a) => {
-            eprintln!(
-                "{}Failed to listen address {}({}): running in RO mode!",
-                Red.paint(" * "),
-                address,
-                a
-            );
+        tried.push(m);
+        if stage_milestone_start(ad, dir, m) {
             return;
         }
-    };
-    let base_dir = "inproc://airup/supervisors/";
-    (*COMM_INIT.write().unwrap()) = true;
-    println!("{}Starting communicating bridges...", Green.paint(" * "));
-    loop {
-        // Find new supervisors
-        let msg = supls.try_recv();
-        if msg.is_ok() {
-            let msg = msg.unwrap();
-            let mut mdir = String::from(base_dir.clone());
-            let mut id = String::from_utf8_lossy(msg.as_slice()).to_string();
-            let mut is_up = true;
-            if id.starts_with("up ") {
-                id = id[3..].to_string();
-            } else if id.starts_with("down ") {
-                id = id[5..].to_string();
-                is_up = false;
-            }
-            if is_up {
-                let skt = Socket::new(Protocol::Pair1);
-                let skt = match skt {
-                    Ok(a) => a,
-                    Err(_) => {
-                        continue;
-                    }
-                };
-                mdir.push_str(&id);
-                let c = skt.dial(&mdir);
-                match c {
-                    Ok(_) => (),
-                    Err(_) => {
-                        continue;
-                    }
-                };
-                sups.insert(id, skt);
-            } else {
-                sups.get_mut(&id).unwrap().close();
-                sups.remove(&id);
-            }
-        }
-        // Detect IPC messages
-        let msg = server.try_recv();
-        if msg.is_ok() {
-            let msg = msg.unwrap();
-            let msg = String::from_utf8_lossy(msg.as_slice());
-            if msg.starts_with("svc ") {
-                let msg = &msg[4..];
-                if msg.starts_with("start ") {
-                    let msg = &msg[6..];
-                    let guard = false;
-                    if guard {
-                        unimplemented!();
-                    } else {
-                        let sp = sups.get(msg.clone());
-                        match sp {
-                            Some(a) => match a.send("up".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => (),
-                            },
-                            None => match server.send("SvcNotRunning".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => (),
-                            },
-                        };
-                    }
-                } else if msg.starts_with("stop ") {
-                    let msg = &msg[5..];
-                    let guard = false;
-                    if guard {
-                        unimplemented!();
-                    } else {
-                        let sp = sups.get(msg.clone());
-                        match sp {
-                            Some(a) => match a.send("down".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => (),
-                            },
-                            None => match server.send("SvcNotRunning".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => (),
-                            },
-                        };
-                    }
-                } else if msg.starts_with("restart ") {
-                    let msg = &msg[8..];
-                    let guard = false;
-                    if guard {
-                        unimplemented!();
-                    } else {
-                        let sp = sups.get(msg.clone());
-                        match sp {
-                            Some(a) => match a.send("restart".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => (),
-                            },
-                            None => match server.send("SvcNotRunning".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => (),
-                            },
-                        };
-                    }
-                } else if msg.starts_with("status ") {
-                    let msg = &msg[7..];
-                    let sp = sups.get(msg.clone());
-                    match sp {
-                        Some(a) => {
-                            let status = svc_running_core(msg);
-                            let status_str = match status {
-                                SvcStatus::Readying => "Readying",
-                                SvcStatus::Running => "Running",
-                                SvcStatus::Restarting => "Restarting",
-                                SvcStatus::Stopped => "Stopped",
-                                SvcStatus::Unmentioned => "SvcNotRunning",
-                            };
-                            let pid = match status {
-                                SvcStatus::Running => match a.send("pid".as_bytes()) {
-                                    Ok(_) => match a.recv() {
-                                        Ok(msg) => {
-                                            String::from_utf8_lossy(msg.as_slice()).to_string()
-                                        }
-                                        Err(_) => "0".to_string(),
-                                    },
-                                    Err(_) => "0".to_string(),
-                                },
-                                _ => "0".to_string(),
-                            };
-                            let mut newmsg = String::from(status_str);
-                            newmsg.push(' ');
-                            newmsg.push_str(&pid);
-                            match server.send(newmsg.as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => {
-                                    continue;
-                                }
-                            };
-                        }
-                        None => {
-                            match server.send("SvcNotRunning".as_bytes()) {
-                                Ok(_) => (),
-                                Err(_) => {
-                                    continue;
-                                }
-                            };
-                        }
-                    };
-                }
-            } else if msg.starts_with("system ") {
-                let msg = &msg[7..];
-                if msg == "poweroff" {
-                    pre_shutdown(ah.clone()).unwrap();
-                    power::poweroff();
-                } else if msg == "reboot" {
-                    pre_shutdown(ah.clone()).unwrap();
-                    power::restart();
-                }
+    }
+}
+// Reach `milestone` in place of the current one, in the background. What
+// it does not need has been stopped already.
+fn switch_milestone(ad: &'static str, milestone: String) -> io::Result<()> {
+    info!("Switching to milestone {}...", milestone);
+    let mut shell = RESCUE_SHELL.lock().unwrap();
+    env::set_var("AIRUP_TARGET_MILESTONE", &milestone);
+    rescue_end(&mut shell);
+    drop(shell);
+    REACHED_MILESTONES.write().unwrap().clear();
+    Builder::new()
+        .name("milestone".to_string())
+        .spawn(move || {
+            let mut dir = PathBuf::from(ad);
+            dir.push("milestones");
+            stage_milestone_start(ad, &dir.to_string_lossy(), &milestone);
+            *MILESTONE_BUSY.write().unwrap() = false;
+        })
+        .map(|_| ())
+}
+// `airupd --check [path]`: without a path, airup.conf and the airup_home
+// it names are checked.
+fn check_mode(path: Option<&String>) -> i32 {
+    let paths = match path {
+        Some(path) => vec![PathBuf::from(path)],
+        None => {
+            let airup_conf = Path::new(AIRUP_CONF);
+            let airup_home = config::read_airup_conf(airup_conf, &mut Report::default()).airup_home;
+            let mut paths = vec![PathBuf::from(airup_home)];
+            if airup_conf.exists() {
+                paths.insert(0, airup_conf.to_path_buf());
             }
+            paths
         }
-    }
+    };
+    config::check_all(&paths)
 }
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if let Some(pos) = args.iter().position(|a| a == "--check") {
+        exit(check_mode(args.get(pos + 1)));
+    }
     pid_detect();
+    supervisor::block_sigchld();
     set_panic();
-    let airup_conf = get_toml_of(AIRUP_CONF);
-    //Prepare some values from airup.conf
-    let osname = g_airupconf(airup_conf.as_ref(), "osname");
-    let osname = osname.as_str().unwrap();
-    pathsetup(
-        g_airupconf(airup_conf.as_ref(), "env_path")
-            .as_str()
-            .unwrap(),
-    );
-    println!(
-        "{} {} is launching {}...",
-        Purple.paint("Airup"),
-        Purple.paint(AIRUP_VERSION.clone()),
-        Green.paint(osname)
+    let mut report = Report::default();
+    let airup_conf = config::read_airup_conf(Path::new(AIRUP_CONF), &mut report);
+    if let Some(path) = &airup_conf.env_path {
+        env::set_var("PATH", path);
+    }
+    journal::setup(JournalConfig {
+        level: airup_conf.log_level,
+        color: match airup_conf.color {
+            Color::Always => Some(true),
+            Color::Never => Some(false),
+            Color::Auto => None,
+        },
+        boot_log: airup_conf.boot_log,
+    });
+    report.log();
+    info!(
+        "Airup {} is launching {}...",
+        AIRUP_VERSION, airup_conf.osname
     );
     let milestone = get_milestone();
-    let airup_home = g_airupconf(airup_conf.as_ref(), "airup_home");
-    let airup_home = airup_home.as_str().unwrap();
-    let airup_home = &*Box::leak(airup_home.to_string().into_boxed_str());
-    let prestart_paral = g_airupconf(airup_conf.as_ref(), "prestart_paral");
-    let prestart_paral = prestart_paral.as_bool().unwrap();
-    set_airenv(&milestone, airup_home.clone(), prestart_paral);
-    let mut prestart_dir = PathBuf::from(airup_home.clone());
-    prestart_dir.push("prestart");
-    stage_prestart_exec(
-        prestart_dir.to_str().unwrap_or(
-            get_default_value("internal", "prestart_dir")
-                .unwrap()
-                .as_str()
-                .unwrap(),
-        ),
-        prestart_paral,
+    let airup_home = &*Box::leak(airup_conf.airup_home.into_boxed_str());
+    let prestart_paral = airup_conf.prestart_paral;
+    set_airenv(&milestone, airup_home, prestart_paral);
+    // Emergency mode skips prestart, in case that is what breaks booting.
+    if milestone != "emergency" {
+        let mut prestart_dir = PathBuf::from(airup_home);
+        prestart_dir.push("prestart");
+        stage_prestart_exec(&prestart_dir.to_string_lossy(), prestart_paral);
+    }
+    journal::persist();
+    let server = ipc::open(&airup_conf.control_groups, &airup_conf.power_groups);
+    let providers = airup_conf.providers;
+    let log_conf = LogConfig {
+        dir: airup_conf.log_dir,
+        max_size: airup_conf.log_max_size,
+        rotate: airup_conf.log_rotate,
+        lines: airup_conf.log_lines,
+    };
+    let thrd = Builder::new().name("supervisor".to_string());
+    let supervisor = thrd
+        .spawn(move || supervisor::supervisor_main(airup_home, providers, log_conf, server))
+        .unwrap();
+    let mut milestones_dir = PathBuf::from(airup_home);
+    milestones_dir.push("milestones");
+    let milestones_dir = milestones_dir.to_string_lossy();
+    stage_milestones(
+        airup_home,
+        &milestones_dir,
+        &milestone,
+        &airup_conf.fallback_milestone,
     );
-    let thrd = Builder::new().name("ipcmgr".to_string());
-    let rwmode = thrd.spawn(move || enable_rw(airup_home.clone())).unwrap();
+    *MILESTONE_BUSY.write().unwrap() = false;
+    journal::persist();
+    if let Err(e) = supervisor.join().unwrap() {
+        error!("The supervisor loop failed: {}!", e);
+    }
     loop {
-        if (*COMM_INIT.read().unwrap()) == true {
-            break;
-        }
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_milestone_from_cmdline() {
+        assert_eq!(milestone_of(""), "default");
+        assert_eq!(
+            milestone_of("BOOT_IMAGE=/vmlinuz root=/dev/sda1 ro quiet\n"),
+            "default"
+        );
+        assert_eq!(milestone_of("ro milestone=server quiet"), "server");
+        assert_eq!(milestone_of("milestone=a milestone=b"), "b");
+        assert_eq!(milestone_of("milestone= ro"), "default");
+        assert_eq!(milestone_of("xmilestone=a"), "default");
+    }
+    #[test]
+    fn builtin_milestones_win() {
+        assert_eq!(milestone_of("airup.rescue milestone=server"), "rescue");
+        assert_eq!(
+            milestone_of("milestone=server airup.emergency"),
+            "emergency"
+        );
+        assert_eq!(milestone_of("airup.rescuex"), "default");
     }
-    let mut milestones_dir = PathBuf::from(airup_home.clone());
-    milestones_dir.push("milestones");
-    let milestones_dir = milestones_dir.to_string_lossy();
-    stage_milestone_start(airup_home.clone(), &milestones_dir, &milestone);
-    rwmode.join().unwrap();
-    loop {}
 }
@@ -1,19 +1,187 @@
-#[cfg(target_os = "linux")]
-use libc::{reboot, LINUX_REBOOT_CMD_POWER_OFF, LINUX_REBOOT_CMD_RESTART};
-use std::process::exit;
-#[cfg(target_os = "linux")]
+use libc::{
+    c_int, kill, mount, sync, LINUX_REBOOT_CMD_HALT, LINUX_REBOOT_CMD_KEXEC,
+    LINUX_REBOOT_CMD_POWER_OFF, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, SIGKILL, SIGTERM,
+};
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    os::unix::process::CommandExt,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long processes left after the services stopped get to exit on
+// SIGTERM before they are killed.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+// How long killed processes get to be gone, so that their files are
+// closed before filesystems are remounted read-only.
+const KILL_WAIT: Duration = Duration::from_secs(2);
+
 pub fn poweroff() {
+    finish();
     reboot(LINUX_REBOOT_CMD_POWER_OFF);
 }
-#[cfg(target_os = "linux")]
 pub fn restart() {
-    reboot(LINUX_REBOOT_CMD_POWER_OFF);
+    finish();
+    reboot(LINUX_REBOOT_CMD_RESTART);
 }
-#[cfg(not(target_os = "linux"))]
-pub fn poweroff() {
-	exit(-1);
+pub fn halt() {
+    finish();
+    reboot(LINUX_REBOOT_CMD_HALT);
 }
-#[cfg(not(target_os = "linux"))]
-pub fn restart() {
-	exit(0);
+// Boot the kernel loaded with kexec_load(2) beforehand, else reboot.
+pub fn kexec() {
+    finish();
+    reboot(LINUX_REBOOT_CMD_KEXEC);
+    reboot(LINUX_REBOOT_CMD_RESTART);
+}
+// Start userspace over: the kernel, and with it the mounts, stay.
+pub fn soft_reboot() {
+    kill_all();
+    unsafe { sync() };
+    let args: Vec<String> = env::args().collect();
+    let e = Command::new("/proc/self/exe")
+        .arg0(&args[0])
+        .args(&args[1..])
+        .exec();
+    error!("Failed to execute airupd again: {}, rebooting instead!", e);
+    restart();
+}
+pub fn kexec_loaded() -> bool {
+    fs::read_to_string("/sys/kernel/kexec_loaded")
+        .map(|a| a.trim() == "1")
+        .unwrap_or(false)
+}
+// The last steps before the kernel takes over: nothing may be left running
+// or unwritten.
+fn finish() {
+    kill_all();
+    info!("Syncing filesystems...");
+    unsafe { sync() };
+    info!("Remounting filesystems read-only...");
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    for target in mount_points(&mounts) {
+        unsafe {
+            mount(
+                std::ptr::null(),
+                target.as_ptr(),
+                std::ptr::null(),
+                MS_REMOUNT | MS_RDONLY,
+                std::ptr::null(),
+            );
+        }
+    }
+    unsafe { sync() };
+}
+fn reboot(cmd: c_int) {
+    if unsafe { libc::reboot(cmd) } != 0 {
+        error!(
+            "Failed to reboot({:#x}): {}!",
+            cmd,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+// Everything but airupd itself is asked to exit, then killed if it does
+// not in time.
+fn kill_all() {
+    info!("Sending SIGTERM to all processes...");
+    unsafe { kill(-1, SIGTERM) };
+    if wait_all(KILL_GRACE) {
+        return;
+    }
+    warn!("Sending SIGKILL to all processes...");
+    unsafe { kill(-1, SIGKILL) };
+    if !wait_all(KILL_WAIT) {
+        error!("Some processes survived SIGKILL!");
+    }
+}
+// Whether every other process is gone before `timeout`.
+fn wait_all(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while processes_left() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+// Whether any process but airupd runs. Kernel threads and zombies have
+// an empty command line.
+fn processes_left() -> bool {
+    let pid = std::process::id().to_string();
+    fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|name| name != &pid && name.bytes().all(|b| b.is_ascii_digit()))
+        .any(|name| {
+            fs::read(format!("/proc/{}/cmdline", name))
+                .map(|a| !a.is_empty())
+                .unwrap_or(false)
+        })
+}
+// Mount points in /proc/mounts, last mounted first, so that one comes
+// before what it was mounted on. /proc/mounts escapes blanks and
+// backslashes in octal.
+fn mount_points(mounts: &str) -> Vec<CString> {
+    mounts
+        .lines()
+        .rev()
+        .filter_map(|l| l.split(' ').nth(1))
+        .filter_map(|path| {
+            let mut bytes = Vec::new();
+            let mut rest = path.as_bytes();
+            while let Some((&b, tail)) = rest.split_first() {
+                match tail.get(..3).and_then(|o| std::str::from_utf8(o).ok()) {
+                    Some(o) if b == b'\\' => match u8::from_str_radix(o, 8) {
+                        Ok(c) => {
+                            bytes.push(c);
+                            rest = &tail[3..];
+                            continue;
+                        }
+                        Err(_) => bytes.push(b),
+                    },
+                    _ => bytes.push(b),
+                }
+                rest = tail;
+            }
+            CString::new(bytes).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(list: &[&str]) -> Vec<CString> {
+        list.iter().map(|a| CString::new(*a).unwrap()).collect()
+    }
+
+    #[test]
+    fn lists_deepest_mounts_first() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                      proc /proc proc rw,nosuid 0 0\n\
+                      /dev/sda2 /usr ext4 rw 0 0\n\
+                      /dev/sda3 /usr/local ext4 rw 0 0\n";
+        assert_eq!(
+            mount_points(mounts),
+            paths(&["/usr/local", "/usr", "/proc", "/"])
+        );
+    }
+    #[test]
+    fn unescapes_mount_points() {
+        let mounts = "/dev/sdb1 /mnt/my\\040disk vfat rw 0 0\n\
+                      /dev/sdb2 /mnt/tab\\011and\\134slash ext4 rw 0 0\n\
+                      /dev/sdb3 /mnt/odd\\9zz ext4 rw 0 0\n";
+        assert_eq!(
+            mount_points(mounts),
+            paths(&["/mnt/odd\\9zz", "/mnt/tab\tand\\slash", "/mnt/my disk"])
+        );
+    }
 }
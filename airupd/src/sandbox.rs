@@ -0,0 +1,493 @@
+use crate::config::ServiceConfig;
+use libc::{
+    c_int, c_long, c_ulong, c_void, ifreq, sock_filter, sock_fprog, AF_INET, CLONE_NEWNET,
+    CLONE_NEWNS, EINVAL, ENOENT, EPERM, IFF_UP, MS_BIND, MS_NODEV, MS_NOSUID, MS_RDONLY, MS_REC,
+    MS_REMOUNT, MS_SLAVE, PR_CAPBSET_DROP, PR_SET_NO_NEW_PRIVS, PR_SET_SECCOMP, SIOCGIFFLAGS,
+    SIOCSIFFLAGS, SOCK_CLOEXEC, SOCK_DGRAM,
+};
+use std::{ffi::CString, io, mem, ptr};
+
+// Hardening of a service's main process. `enter` runs in the forked child
+// while it is still privileged, `lock` right after it dropped privileges.
+#[derive(Clone, Default)]
+pub struct Sandbox {
+    private_tmp: bool,
+    private_network: bool,
+    // A leading '-' in the .svc file makes a path optional.
+    read_only_paths: Vec<(CString, bool)>,
+    no_new_privileges: bool,
+    capabilities: Option<Vec<c_int>>,
+    seccomp: Option<Vec<sock_filter>>,
+    summary: Vec<String>,
+}
+
+const CAPS: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_MODE_FILTER: c_ulong = 2;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+// Architectures we know the system call numbers of.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+// Needed to get from pre_exec into the service at all.
+const ALWAYS_ALLOWED: &[&str] = &["execve", "exit", "exit_group"];
+
+impl Sandbox {
+    pub fn new(cfg: &ServiceConfig) -> Result<Sandbox, String> {
+        // Silently dropping a hardening option would be worse than refusing
+        // the service, so unusable values are errors here.
+        let mut sandbox = Sandbox {
+            private_tmp: cfg.private_tmp,
+            private_network: cfg.private_network,
+            no_new_privileges: cfg.no_new_privileges,
+            ..Default::default()
+        };
+        for path in &cfg.read_only_paths {
+            let (path, optional) = match path.strip_prefix('-') {
+                Some(a) => (a, true),
+                None => (path.as_str(), false),
+            };
+            if !path.starts_with('/') {
+                return Err(format!("read_only_paths: {} is not an absolute path", path));
+            }
+            let path = CString::new(path)
+                .map_err(|_| "read_only_paths contains a NUL byte".to_string())?;
+            sandbox.read_only_paths.push((path, optional));
+        }
+        if let Some(names) = &cfg.capability_bounding_set {
+            let mut caps = Vec::new();
+            for name in names {
+                let short = name.to_lowercase();
+                let short = short.strip_prefix("cap_").unwrap_or(&short);
+                match CAPS.iter().position(|c| *c == short) {
+                    Some(cap) => caps.push(cap as c_int),
+                    None => return Err(format!("unknown capability {}", name)),
+                }
+            }
+            sandbox.capabilities = Some(caps);
+        }
+        if let Some(names) = &cfg.seccomp_allow {
+            let arch = AUDIT_ARCH
+                .ok_or_else(|| "seccomp_allow is not supported on this architecture".to_string())?;
+            let mut nrs = Vec::new();
+            for name in names
+                .iter()
+                .map(|a| a.as_str())
+                .chain(ALWAYS_ALLOWED.iter().copied())
+            {
+                match syscall_nr(name) {
+                    Some(nr) => nrs.push(nr),
+                    None => return Err(format!("unknown system call {}", name)),
+                }
+            }
+            sandbox.seccomp = Some(seccomp_filter(arch, &nrs));
+        }
+        let summary = [
+            ("private_tmp", sandbox.private_tmp),
+            ("private_network", sandbox.private_network),
+            ("read_only_paths", !sandbox.read_only_paths.is_empty()),
+            ("no_new_privileges", sandbox.no_new_privileges),
+            ("capability_bounding_set", sandbox.capabilities.is_some()),
+            ("seccomp", sandbox.seccomp.is_some()),
+        ];
+        sandbox.summary = summary
+            .iter()
+            .filter(|(_, on)| *on)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        Ok(sandbox)
+    }
+    // The options in effect, for `airupctl status`.
+    pub fn summary(&self) -> &[String] {
+        &self.summary
+    }
+    // Runs in the forked child, so it must stick to plain system calls.
+    pub fn enter(&self) -> io::Result<()> {
+        let mut flags = 0;
+        if self.private_tmp || !self.read_only_paths.is_empty() {
+            flags |= CLONE_NEWNS;
+        }
+        if self.private_network {
+            flags |= CLONE_NEWNET;
+        }
+        unsafe {
+            if flags != 0 {
+                check(libc::unshare(flags))?;
+            }
+            if flags & CLONE_NEWNS != 0 {
+                // Keep our mounts from propagating back to the host.
+                check(libc::mount(
+                    ptr::null(),
+                    b"/\0".as_ptr() as *const _,
+                    ptr::null(),
+                    MS_REC | MS_SLAVE,
+                    ptr::null(),
+                ))?;
+            }
+            if self.private_tmp {
+                for dir in &[&b"/tmp\0"[..], &b"/var/tmp\0"[..]] {
+                    let rv = libc::mount(
+                        b"tmpfs\0".as_ptr() as *const _,
+                        dir.as_ptr() as *const _,
+                        b"tmpfs\0".as_ptr() as *const _,
+                        MS_NOSUID | MS_NODEV,
+                        b"mode=1777\0".as_ptr() as *const c_void,
+                    );
+                    if rv < 0 && io::Error::last_os_error().raw_os_error() != Some(ENOENT) {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            for (path, optional) in &self.read_only_paths {
+                let rv = libc::mount(
+                    path.as_ptr(),
+                    path.as_ptr(),
+                    ptr::null(),
+                    MS_BIND | MS_REC,
+                    ptr::null(),
+                );
+                if rv < 0 {
+                    if *optional && io::Error::last_os_error().raw_os_error() == Some(ENOENT) {
+                        continue;
+                    }
+                    return Err(io::Error::last_os_error());
+                }
+                check(libc::mount(
+                    ptr::null(),
+                    path.as_ptr(),
+                    ptr::null(),
+                    MS_BIND | MS_REMOUNT | MS_RDONLY,
+                    ptr::null(),
+                ))?;
+            }
+            if self.private_network {
+                loopback_up()?;
+            }
+            if let Some(caps) = &self.capabilities {
+                // The kernel may know fewer capabilities than we do.
+                for cap in 0..64 {
+                    if caps.contains(&cap) {
+                        continue;
+                    }
+                    if libc::prctl(PR_CAPBSET_DROP, cap as c_ulong, 0, 0, 0) < 0 {
+                        if io::Error::last_os_error().raw_os_error() == Some(EINVAL) {
+                            break;
+                        }
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn lock(&self) -> io::Result<()> {
+        unsafe {
+            // A seccomp filter may only be installed unprivileged with
+            // no_new_privs set.
+            if self.no_new_privileges || self.seccomp.is_some() {
+                check(libc::prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            }
+            if let Some(filter) = &self.seccomp {
+                let prog = sock_fprog {
+                    len: filter.len() as _,
+                    filter: filter.as_ptr() as *mut sock_filter,
+                };
+                check(libc::prctl(
+                    PR_SET_SECCOMP,
+                    SECCOMP_MODE_FILTER,
+                    &prog as *const sock_fprog,
+                    0,
+                    0,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check(rv: c_int) -> io::Result<()> {
+    if rv < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+// A fresh network namespace only has a loopback device, and it is down.
+unsafe fn loopback_up() -> io::Result<()> {
+    let fd = libc::socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0);
+    check(fd)?;
+    let mut req: ifreq = mem::zeroed();
+    req.ifr_name[..3].copy_from_slice(&[b'l' as _, b'o' as _, 0]);
+    let mut rv = libc::ioctl(fd, SIOCGIFFLAGS as _, &mut req);
+    if rv >= 0 {
+        req.ifr_ifru.ifru_flags |= IFF_UP as i16;
+        rv = libc::ioctl(fd, SIOCSIFFLAGS as _, &req);
+    }
+    let err = io::Error::last_os_error();
+    libc::close(fd);
+    if rv < 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+fn stmt(code: u16, k: u32) -> sock_filter {
+    sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code, jt, jf, k }
+}
+// Allow the listed system calls of our own architecture; anything else
+// fails with EPERM, and a foreign architecture kills the process.
+fn seccomp_filter(arch: u32, nrs: &[c_long]) -> Vec<sock_filter> {
+    let mut prog = vec![
+        // seccomp_data.arch
+        stmt(BPF_LD_W_ABS, 4),
+        jump(BPF_JEQ_K, arch, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        // seccomp_data.nr
+        stmt(BPF_LD_W_ABS, 0),
+    ];
+    // x32 system calls come in with this bit set on x86_64.
+    if cfg!(target_arch = "x86_64") {
+        prog.push(jump(BPF_JGE_K, 0x4000_0000, 0, 1));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+    }
+    for nr in nrs {
+        prog.push(jump(BPF_JEQ_K, *nr as u32, 0, 1));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | EPERM as u32));
+    prog
+}
+macro_rules! syscalls {
+    ($($name:ident)*) => {
+        &[$((stringify!($name), libc::$name)),*]
+    };
+}
+fn syscall_nr(name: &str) -> Option<c_long> {
+    let name = format!("SYS_{}", name);
+    SYSCALLS
+        .iter()
+        .chain(ARCH_SYSCALLS.iter())
+        .find(|(n, _)| *n == name)
+        .map(|(_, nr)| *nr)
+}
+// System calls every architecture has.
+const SYSCALLS: &[(&str, c_long)] = syscalls! {
+    SYS_read SYS_write SYS_close SYS_fstat SYS_lseek SYS_mmap SYS_mprotect SYS_munmap SYS_brk
+    SYS_rt_sigaction SYS_rt_sigprocmask SYS_rt_sigreturn SYS_ioctl SYS_pread64 SYS_pwrite64
+    SYS_readv SYS_writev SYS_sched_yield SYS_mremap SYS_msync SYS_mincore SYS_madvise
+    SYS_shmget SYS_shmat SYS_shmctl SYS_shmdt SYS_dup SYS_dup3 SYS_nanosleep SYS_getitimer
+    SYS_setitimer SYS_getpid SYS_sendfile SYS_socket SYS_connect SYS_accept SYS_accept4
+    SYS_sendto SYS_recvfrom SYS_sendmsg SYS_recvmsg SYS_sendmmsg SYS_recvmmsg SYS_shutdown
+    SYS_bind SYS_listen SYS_getsockname SYS_getpeername SYS_socketpair SYS_setsockopt
+    SYS_getsockopt SYS_clone SYS_clone3 SYS_execve SYS_execveat SYS_exit SYS_exit_group
+    SYS_wait4 SYS_waitid SYS_kill SYS_tkill SYS_tgkill SYS_uname SYS_semget SYS_semop
+    SYS_semctl SYS_semtimedop SYS_msgget SYS_msgsnd SYS_msgrcv SYS_msgctl SYS_fcntl SYS_flock
+    SYS_fsync SYS_fdatasync SYS_syncfs SYS_sync SYS_truncate SYS_ftruncate SYS_fallocate
+    SYS_getcwd SYS_chdir SYS_fchdir SYS_fchmod SYS_fchmodat SYS_fchown SYS_fchownat SYS_umask
+    SYS_gettimeofday SYS_getrlimit SYS_setrlimit SYS_prlimit64 SYS_getrusage SYS_sysinfo
+    SYS_times SYS_ptrace SYS_syslog SYS_getuid SYS_getgid SYS_geteuid SYS_getegid SYS_setuid
+    SYS_setgid SYS_setreuid SYS_setregid SYS_setresuid SYS_getresuid SYS_setresgid
+    SYS_getresgid SYS_setfsuid SYS_setfsgid SYS_getgroups SYS_setgroups SYS_setpgid
+    SYS_getpgid SYS_getppid SYS_setsid SYS_getsid SYS_capget SYS_capset SYS_rt_sigpending
+    SYS_rt_sigtimedwait SYS_rt_sigqueueinfo SYS_rt_tgsigqueueinfo SYS_rt_sigsuspend
+    SYS_sigaltstack SYS_personality SYS_statfs SYS_fstatfs SYS_getpriority SYS_setpriority
+    SYS_sched_setparam SYS_sched_getparam SYS_sched_setscheduler SYS_sched_getscheduler
+    SYS_sched_get_priority_max SYS_sched_get_priority_min SYS_sched_rr_get_interval
+    SYS_sched_setaffinity SYS_sched_getaffinity SYS_sched_setattr SYS_sched_getattr SYS_mlock
+    SYS_mlock2 SYS_munlock SYS_mlockall SYS_munlockall SYS_vhangup SYS_pivot_root SYS_prctl
+    SYS_adjtimex SYS_chroot SYS_acct SYS_settimeofday SYS_mount SYS_umount2 SYS_swapon
+    SYS_swapoff SYS_reboot SYS_sethostname SYS_setdomainname SYS_init_module
+    SYS_finit_module SYS_delete_module SYS_quotactl SYS_gettid SYS_readahead SYS_setxattr
+    SYS_lsetxattr SYS_fsetxattr SYS_getxattr SYS_lgetxattr SYS_fgetxattr SYS_listxattr
+    SYS_llistxattr SYS_flistxattr SYS_removexattr SYS_lremovexattr SYS_fremovexattr SYS_futex
+    SYS_io_setup SYS_io_destroy SYS_io_getevents SYS_io_submit SYS_io_cancel SYS_getdents64
+    SYS_set_tid_address SYS_restart_syscall SYS_fadvise64 SYS_timer_create SYS_timer_settime
+    SYS_timer_gettime SYS_timer_getoverrun SYS_timer_delete SYS_clock_settime
+    SYS_clock_gettime SYS_clock_getres SYS_clock_nanosleep SYS_clock_adjtime SYS_epoll_ctl
+    SYS_epoll_pwait SYS_epoll_create1 SYS_mbind SYS_set_mempolicy SYS_get_mempolicy
+    SYS_migrate_pages SYS_move_pages SYS_mq_open SYS_mq_unlink SYS_mq_timedsend
+    SYS_mq_timedreceive SYS_mq_notify SYS_mq_getsetattr SYS_kexec_load SYS_add_key
+    SYS_request_key SYS_keyctl SYS_ioprio_set SYS_ioprio_get SYS_inotify_init1
+    SYS_inotify_add_watch SYS_inotify_rm_watch SYS_openat SYS_mkdirat SYS_mknodat
+    SYS_newfstatat SYS_unlinkat SYS_renameat SYS_renameat2 SYS_linkat SYS_symlinkat
+    SYS_readlinkat SYS_faccessat SYS_faccessat2 SYS_pselect6 SYS_ppoll SYS_unshare SYS_setns
+    SYS_set_robust_list SYS_get_robust_list SYS_splice SYS_tee SYS_vmsplice
+    SYS_sync_file_range SYS_utimensat SYS_timerfd_create SYS_timerfd_settime
+    SYS_timerfd_gettime SYS_signalfd4 SYS_eventfd2 SYS_pipe2 SYS_preadv SYS_pwritev
+    SYS_preadv2 SYS_pwritev2 SYS_perf_event_open SYS_fanotify_init SYS_fanotify_mark
+    SYS_name_to_handle_at SYS_open_by_handle_at SYS_getcpu SYS_process_vm_readv
+    SYS_process_vm_writev SYS_kcmp SYS_seccomp SYS_getrandom SYS_memfd_create SYS_bpf
+    SYS_membarrier SYS_copy_file_range SYS_statx SYS_rseq SYS_pidfd_open SYS_pidfd_send_signal
+    SYS_close_range
+};
+// Older system calls only some architectures still have.
+#[cfg(target_arch = "x86_64")]
+const ARCH_SYSCALLS: &[(&str, c_long)] = syscalls! {
+    SYS_open SYS_stat SYS_lstat SYS_poll SYS_access SYS_pipe SYS_select SYS_dup2 SYS_pause
+    SYS_alarm SYS_fork SYS_vfork SYS_getdents SYS_creat SYS_mkdir SYS_rmdir SYS_link
+    SYS_unlink SYS_symlink SYS_readlink SYS_chmod SYS_chown SYS_lchown SYS_rename SYS_getpgrp
+    SYS_utime SYS_utimes SYS_futimesat SYS_time SYS_epoll_create SYS_epoll_wait
+    SYS_inotify_init SYS_signalfd SYS_eventfd SYS_arch_prctl SYS_mknod
+};
+#[cfg(not(target_arch = "x86_64"))]
+const ARCH_SYSCALLS: &[(&str, c_long)] = &[];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(cfg: ServiceConfig) -> Result<Sandbox, String> {
+        Sandbox::new(&cfg)
+    }
+    fn insn(f: &sock_filter) -> (u16, u8, u8, u32) {
+        (f.code, f.jt, f.jf, f.k)
+    }
+
+    #[test]
+    fn builds_seccomp_filter() {
+        let prog: Vec<_> = seccomp_filter(0xc000_003e, &[0, 60])
+            .iter()
+            .map(insn)
+            .collect();
+        let head = vec![
+            (BPF_LD_W_ABS, 0, 0, 4),
+            (BPF_JEQ_K, 1, 0, 0xc000_003e),
+            (BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+            (BPF_LD_W_ABS, 0, 0, 0),
+        ];
+        let x32 = if cfg!(target_arch = "x86_64") {
+            vec![
+                (BPF_JGE_K, 0, 1, 0x4000_0000),
+                (BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+            ]
+        } else {
+            Vec::new()
+        };
+        let tail = vec![
+            (BPF_JEQ_K, 0, 1, 0),
+            (BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+            (BPF_JEQ_K, 0, 1, 60),
+            (BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+            (BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | EPERM as u32),
+        ];
+        assert_eq!(prog, [head, x32, tail].concat());
+    }
+    #[test]
+    fn knows_system_calls() {
+        assert_eq!(syscall_nr("read"), Some(libc::SYS_read));
+        assert_eq!(syscall_nr("exit_group"), Some(libc::SYS_exit_group));
+        assert_eq!(syscall_nr("frobnicate"), None);
+    }
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn always_allows_exec_and_exit() {
+        let sb = sandbox(ServiceConfig {
+            seccomp_allow: Some(vec!["read".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let allowed: Vec<u32> = sb
+            .seccomp
+            .unwrap()
+            .windows(2)
+            .filter(|w| w[0].code == BPF_JEQ_K && w[1].k == SECCOMP_RET_ALLOW)
+            .map(|w| w[0].k)
+            .collect();
+        let expected: Vec<u32> = [libc::SYS_read, libc::SYS_execve, libc::SYS_exit]
+            .iter()
+            .chain([libc::SYS_exit_group].iter())
+            .map(|nr| *nr as u32)
+            .collect();
+        assert_eq!(allowed, expected);
+        assert_eq!(sb.summary, vec!["seccomp".to_string()]);
+    }
+    #[test]
+    fn rejects_unknown_names() {
+        let err = sandbox(ServiceConfig {
+            seccomp_allow: Some(vec!["frobnicate".to_string()]),
+            ..Default::default()
+        });
+        if AUDIT_ARCH.is_some() {
+            assert_eq!(err.err().unwrap(), "unknown system call frobnicate");
+        }
+        let err = sandbox(ServiceConfig {
+            capability_bounding_set: Some(vec!["CAP_FROBNICATE".to_string()]),
+            ..Default::default()
+        });
+        assert_eq!(err.err().unwrap(), "unknown capability CAP_FROBNICATE");
+        let err = sandbox(ServiceConfig {
+            read_only_paths: vec!["relative".to_string()],
+            ..Default::default()
+        });
+        assert!(err.is_err());
+    }
+    #[test]
+    fn maps_capability_names() {
+        let sb = sandbox(ServiceConfig {
+            capability_bounding_set: Some(vec![
+                "CAP_CHOWN".to_string(),
+                "net_bind_service".to_string(),
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(sb.capabilities, Some(vec![0, 10]));
+    }
+}
@@ -0,0 +1,2151 @@
+use crate::{
+    asystem,
+    attrs::ProcAttrs,
+    cgroup::{Cgroup, Hierarchy},
+    config::{self, Ready, Report, Restart, ServiceConfig, StopWay},
+    creds::Identity,
+    environ::Environ,
+    ipc,
+    logs::{LogConfig, ServiceLog},
+    sandbox::Sandbox,
+    send_signal, Cmdline, ExecCtx, Stage, CURRENT_STAGE,
+};
+use libairup::ipc::ExitStatus;
+use libc::{
+    c_char, c_int, c_void, epoll_event, inotify_event, iovec, itimerspec, msghdr, pid_t,
+    sa_family_t, sigaddset, sigemptyset, signalfd_siginfo, sigset_t, sockaddr, sockaddr_un,
+    socklen_t, timespec, ucred, waitpid, AF_UNIX, CLOCK_MONOTONIC, EFD_CLOEXEC, EFD_NONBLOCK,
+    EINTR, EPOLLIN, EPOLL_CLOEXEC, EPOLL_CTL_ADD, IN_CLOEXEC, IN_MODIFY, IN_NONBLOCK, MSG_DONTWAIT,
+    SCM_CREDENTIALS, SFD_CLOEXEC, SFD_NONBLOCK, SIGABRT, SIGCHLD, SIGHUP, SIGINT, SIGKILL, SIGPIPE,
+    SIGTERM, SIG_BLOCK, SOCK_CLOEXEC, SOCK_DGRAM, SOCK_NONBLOCK, SOL_SOCKET, SO_PASSCRED,
+    TFD_CLOEXEC, TFD_NONBLOCK, WNOHANG,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    env,
+    ffi::CString,
+    fs, io, mem,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt, io::RawFd},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Sender},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use toml::{map::Map, Value};
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum SvcStatus {
+    Readying,
+    Running,
+    Restarting,
+    Stopping,
+    Stopped,
+    Unmentioned,
+}
+pub enum SupCmd {
+    Load(PathBuf, Option<Sender<bool>>),
+    LoadAll(Vec<PathBuf>, Sender<Option<usize>>, Sender<bool>),
+    Stop(String),
+    Restart(String),
+    StopAll,
+}
+// A child of airupd itself rather than of a service.
+#[derive(PartialEq, Copy, Clone)]
+enum OwnChild {
+    Running,
+    Exited(c_int),
+    // Nobody waits for it; it is reaped and forgotten.
+    Detached,
+}
+// Where a service is in its lifecycle. Every transition happens on the
+// supervisor thread, driven by child exits, timers or commands.
+#[derive(PartialEq, Copy, Clone)]
+enum Phase {
+    Idle,
+    WaitDeps,
+    PreExec,
+    Launching,
+    Readying,
+    Running,
+    Backoff,
+    PreStop,
+    Stopping,
+    Cleanup,
+}
+#[derive(PartialEq, Copy, Clone)]
+enum Role {
+    Main,
+    Launcher,
+    PreExec,
+    PreStop,
+    StopCmd,
+    Cleanup,
+}
+#[derive(PartialEq, Copy, Clone)]
+enum TimerKind {
+    Ready,
+    Kill,
+    PidFile,
+    Watchdog,
+    Restart,
+}
+// What a reload did, by service id.
+#[derive(Default)]
+pub struct Reloaded {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub needs_restart: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+static SVC_STATUS: Lazy<Mutex<HashMap<String, SvcStatus>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static SVC_STATUS_CHANGED: Lazy<Condvar> = Lazy::new(Condvar::new);
+// airupd's own children: shutdown scripts, milestone pre_exec commands and
+// rescue shells. The supervisor reaps them along with everything else, and
+// hands the status back to whoever waits for them.
+static OWN_CHILDREN: Lazy<Mutex<HashMap<pid_t, OwnChild>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static OWN_CHILD_EXITED: Lazy<Condvar> = Lazy::new(Condvar::new);
+static SUP_QUEUE: Lazy<Mutex<Vec<SupCmd>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static SUP_WAKE: Lazy<RawFd> =
+    Lazy::new(|| unsafe { libc::eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) });
+
+const TOKEN_SIGNAL: u64 = 0;
+const TOKEN_TIMER: u64 = 1;
+const TOKEN_WAKE: u64 = 2;
+const TOKEN_IPC: u64 = 3;
+const TOKEN_NOTIFY: u64 = 4;
+const TOKEN_CGROUP: u64 = 5;
+// Service log pipes are registered as TOKEN_LOG + their index.
+const TOKEN_LOG: u64 = 16;
+// .svc keys a service that is up can take a new value of right away: they
+// only matter when it is stopped, restarted or depended on.
+const LIVE_KEYS: &[&str] = &[
+    "prompt",
+    "description",
+    "dependencies",
+    "provides",
+    "action_user",
+    "pre_stop",
+    "stop_way",
+    "cleanup",
+    "pre_restart",
+    "restart_way",
+    "cleanup_on_restart",
+    "restart",
+    "success_exit_codes",
+    "restart_delay",
+    "restart_delay_max",
+    "retry_time",
+    "retry_window",
+    "kill_timeout",
+    "memory_max",
+    "cpu_weight",
+    "pids_max",
+];
+
+// Service status registry, shared with the other threads.
+pub fn svc_running_core(id: &str) -> SvcStatus {
+    *SVC_STATUS
+        .lock()
+        .unwrap()
+        .get(id)
+        .unwrap_or(&SvcStatus::Unmentioned)
+}
+pub fn svc_wait_all_stopped(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut status = SVC_STATUS.lock().unwrap();
+    loop {
+        if status.values().all(|s| *s == SvcStatus::Stopped) {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        status = SVC_STATUS_CHANGED
+            .wait_timeout(status, deadline - now)
+            .unwrap()
+            .0;
+    }
+}
+fn regsvc(id: &str, stat: SvcStatus) {
+    SVC_STATUS.lock().unwrap().insert(id.to_string(), stat);
+    SVC_STATUS_CHANGED.notify_all();
+}
+// Start a child of airupd's own with `spawn`, registered before it can
+// exit so that the supervisor does not take it for an orphan. A child
+// spawned with `wait` must be waited for with `own_wait`.
+pub fn own_spawn<F: FnOnce() -> Option<pid_t>>(wait: bool, spawn: F) -> Option<pid_t> {
+    let mut children = OWN_CHILDREN.lock().unwrap();
+    let pid = spawn()?;
+    let state = if wait {
+        OwnChild::Running
+    } else {
+        OwnChild::Detached
+    };
+    children.insert(pid, state);
+    Some(pid)
+}
+pub fn own_wait(pid: pid_t) -> c_int {
+    let mut children = OWN_CHILDREN.lock().unwrap();
+    loop {
+        match children.get(&pid) {
+            Some(OwnChild::Exited(status)) => {
+                let status = *status;
+                children.remove(&pid);
+                return status;
+            }
+            Some(OwnChild::Running) => children = OWN_CHILD_EXITED.wait(children).unwrap(),
+            _ => return 0,
+        }
+    }
+}
+// Hand a command to the supervisor thread.
+pub fn sup_send(cmd: SupCmd) {
+    SUP_QUEUE.lock().unwrap().push(cmd);
+    let one: u64 = 1;
+    unsafe {
+        libc::write(*SUP_WAKE, &one as *const u64 as *const c_void, 8);
+    }
+}
+// Start a whole set of services at once, so that their dependency graph
+// is checked as one. Returns None if it was rejected, else whether every
+// service came to run; services that fail to load are only logged and
+// left out.
+pub fn svcrun_all(svctomlpaths: &[String]) -> Option<bool> {
+    let (tx, rx) = channel();
+    let (ready_tx, ready_rx) = channel();
+    sup_send(SupCmd::LoadAll(
+        svctomlpaths.iter().map(PathBuf::from).collect(),
+        tx,
+        ready_tx,
+    ));
+    let started = rx.recv().ok().flatten()?;
+    let mut ran = 0;
+    for _ in 0..started {
+        if ready_rx.recv().unwrap_or(false) {
+            ran += 1;
+        }
+    }
+    Some(ran == svctomlpaths.len())
+}
+// Start one service, blocking until the service is running (true) or has
+// failed to start (false).
+pub fn svcrun_wait(svctomlpath: &str) -> bool {
+    let (tx, rx) = channel();
+    sup_send(SupCmd::Load(PathBuf::from(svctomlpath), Some(tx)));
+    rx.recv().unwrap_or(false)
+}
+pub fn svcid_detect(svctomlpath: &str) -> String {
+    if fs::symlink_metadata(svctomlpath)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false)
+    {
+        let rep = fs::read_link(svctomlpath).unwrap();
+        svcid_detect(&rep.to_string_lossy())
+    } else {
+        let n = &Path::new(svctomlpath)
+            .file_name()
+            .unwrap()
+            .to_string_lossy();
+        n.replace(".svc", "")
+    }
+}
+pub fn block_sigchld() {
+    let mut set = sigchld_set();
+    unsafe {
+        libc::pthread_sigmask(SIG_BLOCK, &mut set as *mut sigset_t, std::ptr::null_mut());
+    }
+}
+fn sigchld_set() -> sigset_t {
+    unsafe {
+        let mut set: sigset_t = mem::zeroed();
+        sigemptyset(&mut set as *mut sigset_t);
+        sigaddset(&mut set as *mut sigset_t, SIGCHLD);
+        set
+    }
+}
+
+struct Service {
+    id: String,
+    source: PathBuf,
+    // The [svc] table the service was built from.
+    defn: Value,
+    // A definition read while the service was up, for its next start.
+    pending: Option<Box<Service>>,
+    prompt: String,
+    desc: String,
+    env: Environ,
+    take_io: bool,
+    log: Option<usize>,
+    user: Identity,
+    action_user: Identity,
+    deps: Vec<String>,
+    provides: Vec<String>,
+    dep_ids: Vec<String>,
+    pre_exec: Option<Cmdline>,
+    exec: Option<Cmdline>,
+    pid_file: Option<String>,
+    pre_stop: Option<Cmdline>,
+    stop_way: StopWay,
+    cleanup: Option<Cmdline>,
+    pre_restart: Option<Cmdline>,
+    restart_way: StopWay,
+    cleanup_on_restart: bool,
+    restart: Restart,
+    success_exit_codes: Vec<i32>,
+    restart_delay: u64,
+    restart_delay_max: u64,
+    retry_time: i64,
+    retry_window: u64,
+    ready_timeout: Option<u64>,
+    ready_notify: bool,
+    watchdog_timeout: Option<u64>,
+    kill_timeout: u64,
+    attrs: ProcAttrs,
+    sandbox: Sandbox,
+    // cgroup files and the values written to them.
+    limits: Vec<(&'static str, String)>,
+    cgroup: Option<Cgroup>,
+    // Status of a main process that exited while the cgroup still had
+    // processes; the service exits with it once the cgroup is empty.
+    pending_exit: Option<c_int>,
+    phase: Phase,
+    pid: pid_t,
+    retry: bool,
+    restarts: Vec<Instant>,
+    last_exit: Option<ExitStatus>,
+    // Why the service last failed to come up or stay up.
+    failure: Option<String>,
+    restarting: bool,
+    timer_gen: u64,
+    status_text: String,
+    last_ping: Instant,
+}
+impl Service {
+    fn new(id: &str, source: &Path, cfg: ServiceConfig, defn: Value) -> Result<Service, String> {
+        cfg.validate()?;
+        let attrs = ProcAttrs::new(&cfg)?;
+        let sandbox = Sandbox::new(&cfg)?;
+        let ms = |d: Duration| d.as_millis().try_into().unwrap_or(u64::MAX);
+        let stop_way = cfg.stop_way;
+        let restart_way = cfg.restart_way.unwrap_or_else(|| stop_way.clone());
+        Ok(Service {
+            id: id.to_string(),
+            source: source.to_path_buf(),
+            defn,
+            pending: None,
+            prompt: cfg.prompt.unwrap_or_else(|| id.to_string()),
+            desc: cfg.description,
+            take_io: cfg.take_io,
+            log: None,
+            env: Environ {
+                clear: cfg.env_clear,
+                expand: cfg.env_expand,
+                files: cfg.env_file,
+                ..Environ::new(&cfg.env_list)
+            },
+            user: Identity {
+                user: cfg.user,
+                group: cfg.group,
+                groups: cfg.supplementary_groups,
+            },
+            action_user: Identity::new(cfg.action_user),
+            deps: cfg.dependencies,
+            provides: cfg.provides,
+            dep_ids: Vec::new(),
+            pre_exec: cfg.pre_exec,
+            exec: cfg.exec,
+            pid_file: cfg.pid_file,
+            pre_stop: cfg.pre_stop,
+            restart_way,
+            stop_way,
+            cleanup: cfg.cleanup,
+            pre_restart: cfg.pre_restart,
+            cleanup_on_restart: cfg.cleanup_on_restart,
+            restart: cfg.restart,
+            success_exit_codes: cfg.success_exit_codes,
+            restart_delay: ms(cfg.restart_delay),
+            restart_delay_max: ms(cfg.restart_delay_max),
+            retry_window: ms(cfg.retry_window),
+            retry_time: cfg.retry_time.into(),
+            ready_timeout: cfg.ready_timeout.map(ms),
+            ready_notify: cfg.ready == Ready::Notify,
+            watchdog_timeout: cfg.watchdog_timeout.map(ms).filter(|a| *a > 0),
+            kill_timeout: ms(cfg.kill_timeout),
+            attrs,
+            sandbox,
+            // Written to the cgroup as they are, so "512M" and "max" work too.
+            limits: vec![
+                ("memory.max", cfg.memory_max),
+                ("cpu.weight", cfg.cpu_weight),
+                ("pids.max", cfg.pids_max),
+            ]
+            .into_iter()
+            .filter_map(|(file, val)| val.map(|a| (file, a.to_string())))
+            .collect(),
+            cgroup: None,
+            pending_exit: None,
+            phase: Phase::Idle,
+            pid: 0,
+            retry: true,
+            restarts: Vec::new(),
+            last_exit: None,
+            failure: None,
+            restarting: false,
+            timer_gen: 0,
+            status_text: String::new(),
+            last_ping: Instant::now(),
+        })
+    }
+    fn status(&self) -> SvcStatus {
+        match self.phase {
+            Phase::Idle => SvcStatus::Stopped,
+            Phase::WaitDeps | Phase::PreExec | Phase::Launching | Phase::Readying => {
+                SvcStatus::Readying
+            }
+            Phase::Running => SvcStatus::Running,
+            Phase::Backoff => SvcStatus::Restarting,
+            Phase::PreStop | Phase::Stopping | Phase::Cleanup => {
+                if self.restarting {
+                    SvcStatus::Restarting
+                } else {
+                    SvcStatus::Stopping
+                }
+            }
+        }
+    }
+    fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+        regsvc(&self.id, self.status());
+    }
+    // Exiting with one of success_exit_codes, or being killed by one of the
+    // signals used to stop services, is a clean exit.
+    fn is_clean(&self, exit: ExitStatus) -> bool {
+        match exit {
+            ExitStatus::Exited { code } => self.success_exit_codes.contains(&code),
+            ExitStatus::Killed { signal, .. } => {
+                matches!(signal, SIGHUP | SIGINT | SIGTERM | SIGPIPE)
+            }
+        }
+    }
+    fn wants_restart(&self, exit: ExitStatus) -> bool {
+        let clean = self.is_clean(exit);
+        match self.restart {
+            Restart::Always => true,
+            Restart::OnFailure => !clean,
+            Restart::OnAbnormal => !clean && matches!(exit, ExitStatus::Killed { .. }),
+            Restart::Never => false,
+        }
+    }
+    // The main process, or once it is gone, whatever is left in the cgroup.
+    fn signal(&self, sig: c_int) {
+        match &self.cgroup {
+            Some(cgroup) if self.pid == 0 => cgroup.signal(sig, 0),
+            _ if self.pid > 0 => {
+                send_signal(self.pid, sig);
+            }
+            _ => (),
+        }
+    }
+    // Whether `pid` is one of the service's processes: in its cgroup, or
+    // forked by its main process. Only such a process may be made
+    // the main one, as we signal and kill it as root.
+    fn owns(&self, pid: pid_t) -> bool {
+        let in_cgroup = self.cgroup.as_ref().map(|a| a.pids().contains(&pid));
+        in_cgroup == Some(true) || (self.pid > 0 && descends_from(pid, self.pid))
+    }
+    fn has_leftovers(&self) -> bool {
+        self.cgroup.as_ref().map(|a| a.populated()).unwrap_or(false)
+    }
+    fn is_up(&self) -> bool {
+        matches!(
+            self.phase,
+            Phase::Launching | Phase::Readying | Phase::Running
+        )
+    }
+    fn latest_defn(&self) -> &Value {
+        self.pending.as_ref().map(|a| &a.defn).unwrap_or(&self.defn)
+    }
+    // Take over a new definition, keeping the state of the instance.
+    fn redefine(&mut self, new: Service) {
+        let old = mem::replace(self, new);
+        self.log = old.log;
+        self.dep_ids = old.dep_ids;
+        self.cgroup = old.cgroup;
+        self.pending_exit = old.pending_exit;
+        self.phase = old.phase;
+        self.pid = old.pid;
+        self.retry = old.retry;
+        self.restarts = old.restarts;
+        self.last_exit = old.last_exit;
+        self.failure = old.failure;
+        self.restarting = old.restarting;
+        self.timer_gen = old.timer_gen;
+        self.status_text = old.status_text;
+        self.last_ping = old.last_ping;
+    }
+    // Take over the settings behind LIVE_KEYS.
+    fn update(&mut self, new: &Service) {
+        self.prompt = new.prompt.clone();
+        self.desc = new.desc.clone();
+        self.deps = new.deps.clone();
+        self.provides = new.provides.clone();
+        self.action_user = new.action_user.clone();
+        self.pre_stop = new.pre_stop.clone();
+        self.stop_way = new.stop_way.clone();
+        self.cleanup = new.cleanup.clone();
+        self.pre_restart = new.pre_restart.clone();
+        self.restart_way = new.restart_way.clone();
+        self.cleanup_on_restart = new.cleanup_on_restart;
+        self.restart = new.restart;
+        self.success_exit_codes = new.success_exit_codes.clone();
+        self.restart_delay = new.restart_delay;
+        self.restart_delay_max = new.restart_delay_max;
+        self.retry_time = new.retry_time;
+        self.retry_window = new.retry_window;
+        self.kill_timeout = new.kill_timeout;
+        self.limits = new.limits.clone();
+    }
+    fn set_limits(&self) {
+        let cgroup = match &self.cgroup {
+            Some(a) => a,
+            None => return,
+        };
+        for (file, val) in &self.limits {
+            if let Err(e) = cgroup.set(file, val) {
+                warn!("Failed to set {} of service {}({})!", file, self.id, e);
+            }
+        }
+    }
+}
+
+pub struct Supervisor {
+    airup_dir: &'static str,
+    providers: HashMap<String, String>,
+    services: HashMap<String, Service>,
+    children: HashMap<pid_t, (String, Role)>,
+    timers: BTreeMap<(Instant, u64), (String, TimerKind, u64)>,
+    timer_seq: u64,
+    timerfd: RawFd,
+    orphans_reaped: u64,
+    waiters: HashMap<String, Vec<Sender<bool>>>,
+    notify_path: Option<String>,
+    epfd: RawFd,
+    log_conf: LogConfig,
+    logs: Vec<ServiceLog>,
+    cgroups: Option<Hierarchy>,
+    inotify: RawFd,
+    // inotify watch descriptor of each service's cgroup.events.
+    watches: HashMap<c_int, String>,
+}
+impl Supervisor {
+    fn load(&mut self, path: &Path) -> Option<String> {
+        self.try_load(path)
+            .map_err(|e| error!("Refusing to load {}!", e))
+            .ok()
+    }
+    fn try_load(&mut self, path: &Path) -> Result<String, String> {
+        let id = svcid_detect(&path.to_string_lossy());
+        if self.services.contains_key(&id) {
+            return Ok(id);
+        }
+        let mut report = Report::default();
+        let cfg = config::read_service(path, &mut report);
+        for warning in &report.warnings {
+            warn!("{}", warning);
+        }
+        let (cfg, defn) = match (cfg, report.errors.first()) {
+            (Some(a), None) => a,
+            (_, e) => {
+                return Err(format!(
+                    "service {}: {}",
+                    id,
+                    e.map(|e| e.as_str()).unwrap_or("invalid configuration")
+                ))
+            }
+        };
+        let svc =
+            Service::new(&id, path, cfg, defn).map_err(|e| format!("service {}: {}", id, e))?;
+        self.services.insert(id.clone(), svc);
+        regsvc(&id, SvcStatus::Stopped);
+        Ok(id)
+    }
+    fn load_dep(&mut self, dep: &str) -> Option<String> {
+        if let Some(name) = dep.strip_prefix("alias::") {
+            return self.resolve_alias(name);
+        }
+        let mut e = PathBuf::from(self.airup_dir);
+        e.push("svc");
+        e.push(format!("{}.svc", dep));
+        self.load(&e)
+    }
+    // Find the service providing `name`: one that is already up, then the
+    // provider preferred in airup.conf, then the only (or first) candidate
+    // under airup_home/svc.
+    fn resolve_alias(&mut self, name: &str) -> Option<String> {
+        let provides = |svc: &Service| svc.provides.iter().any(|p| p == name);
+        if let Some(svc) = self
+            .services
+            .values()
+            .find(|svc| provides(svc) && svc.phase != Phase::Idle)
+        {
+            return Some(svc.id.clone());
+        }
+        if let Some(id) = self.providers.get(name).cloned() {
+            match self.load_dep(&id) {
+                Some(id) if provides(&self.services[&id]) => return Some(id),
+                _ => warn!("Preferred provider {} of {} is unusable!", id, name),
+            }
+        }
+        let mut candidates = Vec::new();
+        for file in svc_files(&Path::new(self.airup_dir).join("svc")) {
+            let id = svcid_detect(&file.to_string_lossy());
+            let found = match self.services.get(&id) {
+                Some(svc) => provides(svc),
+                None => config::read_service(&file, &mut Report::default())
+                    .map(|(a, _)| a.provides.iter().any(|p| p == name))
+                    .unwrap_or(false),
+            };
+            if found {
+                candidates.push(file);
+            }
+        }
+        if candidates.len() > 1 {
+            warn!("Several services provide {}, choosing {}. Set providers.{} in airup.conf to pick one.", name, svcid_detect(&candidates[0].to_string_lossy()), name);
+        }
+        match candidates.first() {
+            Some(file) => self.load(file),
+            None => {
+                error!("No service provides {}!", name);
+                None
+            }
+        }
+    }
+    // Load everything the idle services in `roots` need and sort it into
+    // waves: a service only depends on services of earlier waves, or on
+    // ones that are already up. Missing dependencies and cycles are reported
+    // before anything is started.
+    fn plan(&mut self, roots: &[String]) -> Result<Vec<Vec<String>>, String> {
+        let mut queue: Vec<String> = roots.to_vec();
+        let mut nodes: Vec<String> = Vec::new();
+        let mut missing: Vec<String> = Vec::new();
+        while let Some(id) = queue.pop() {
+            if nodes.contains(&id) || self.services[&id].phase != Phase::Idle {
+                continue;
+            }
+            let mut dep_ids = Vec::new();
+            for dep in self.services[&id].deps.clone() {
+                match self.load_dep(&dep) {
+                    Some(dep) => {
+                        queue.push(dep.clone());
+                        dep_ids.push(dep);
+                    }
+                    None => missing.push(format!("{} needs {}", id, dep)),
+                }
+            }
+            self.services.get_mut(&id).unwrap().dep_ids = dep_ids;
+            nodes.push(id);
+        }
+        if !missing.is_empty() {
+            return Err(format!("missing dependencies ({})", missing.join(", ")));
+        }
+        let mut waves: Vec<Vec<String>> = Vec::new();
+        let mut placed: Vec<String> = Vec::new();
+        while placed.len() < nodes.len() {
+            let mut wave: Vec<String> = nodes
+                .iter()
+                .filter(|id| !placed.contains(id))
+                .filter(|id| {
+                    self.services[*id]
+                        .dep_ids
+                        .iter()
+                        .all(|dep| placed.contains(dep) || !nodes.contains(dep))
+                })
+                .cloned()
+                .collect();
+            if wave.is_empty() {
+                let left: Vec<String> = nodes
+                    .iter()
+                    .filter(|id| !placed.contains(id))
+                    .cloned()
+                    .collect();
+                return Err(format!("dependency cycle ({})", self.find_cycle(&left)));
+            }
+            wave.sort();
+            placed.extend(wave.iter().cloned());
+            waves.push(wave);
+        }
+        Ok(waves)
+    }
+    // Every service in `left` has a dependency in `left`, so walking those
+    // dependencies must come back to a service already seen.
+    fn find_cycle(&self, left: &[String]) -> String {
+        let mut path: Vec<&String> = vec![&left[0]];
+        loop {
+            let next = self.services[path[path.len() - 1]]
+                .dep_ids
+                .iter()
+                .find(|dep| left.contains(dep))
+                .unwrap();
+            if let Some(pos) = path.iter().position(|id| *id == next) {
+                let mut cycle: Vec<&str> = path[pos..].iter().map(|id| id.as_str()).collect();
+                cycle.push(next);
+                return cycle.join(" -> ");
+            }
+            path.push(next);
+        }
+    }
+    fn start(&mut self, ids: &[String]) -> Result<(), String> {
+        let waves = match self.plan(ids) {
+            Ok(a) => a,
+            Err(e) => {
+                error!("Refusing to start {}: {}!", ids.join(", "), e);
+                return Err(e);
+            }
+        };
+        for (n, wave) in waves.iter().enumerate() {
+            debug!("Start wave {}: {}", n, wave.join(", "));
+        }
+        for id in waves.iter().flatten() {
+            let svc = self.services.get_mut(id).unwrap();
+            svc.retry = true;
+            svc.restarts.clear();
+            svc.failure = None;
+            svc.set_phase(Phase::WaitDeps);
+        }
+        self.poll_deps();
+        Ok(())
+    }
+    // Launch every service whose dependencies have all come up, and fail
+    // those with a dependency that is down for good.
+    fn poll_deps(&mut self) {
+        loop {
+            let mut failed: Vec<(String, String)> = self
+                .services
+                .values()
+                .filter(|svc| svc.phase == Phase::WaitDeps)
+                .filter_map(|svc| {
+                    svc.dep_ids
+                        .iter()
+                        .find(|dep| self.services.get(*dep).map(|d| d.phase) == Some(Phase::Idle))
+                        .map(|dep| (svc.id.clone(), dep.clone()))
+                })
+                .collect();
+            failed.sort();
+            for (id, dep) in &failed {
+                let svc = self.services.get_mut(id).unwrap();
+                error!(
+                    "Failed to start service {}: its dependency {} is not running!",
+                    svc.prompt, dep
+                );
+                svc.failure = Some(format!("dependency {} is not running", dep));
+                svc.retry = false;
+                svc.set_phase(Phase::Idle);
+            }
+            let mut ready: Vec<String> = self
+                .services
+                .values()
+                .filter(|svc| svc.phase == Phase::WaitDeps)
+                .filter(|svc| {
+                    svc.dep_ids.iter().all(|dep| match self.services.get(dep) {
+                        Some(d) => d.phase == Phase::Running,
+                        None => true,
+                    })
+                })
+                .map(|svc| svc.id.clone())
+                .collect();
+            ready.sort();
+            if failed.is_empty() && ready.is_empty() {
+                return;
+            }
+            for id in ready {
+                self.begin_exec(&id);
+            }
+        }
+    }
+    fn begin_exec(&mut self, id: &str) {
+        self.apply_pending(id);
+        self.open_log(id);
+        let svc = self.services.get_mut(id).unwrap();
+        let logs = &self.logs;
+        let log = svc.log.map(|i| &logs[i]);
+        if let Some(pre_exec) = svc.pre_exec.clone() {
+            if let Some(pid) = asystem(
+                &svc.action_user,
+                &pre_exec,
+                &svc.env,
+                ExecCtx {
+                    log,
+                    attrs: Some(&svc.attrs),
+                    ..Default::default()
+                },
+            ) {
+                svc.set_phase(Phase::PreExec);
+                self.children.insert(pid, (id.to_string(), Role::PreExec));
+                return;
+            }
+        }
+        self.spawn_main(id);
+    }
+    fn spawn_main(&mut self, id: &str) {
+        self.open_cgroup(id);
+        let svc = self.services.get_mut(id).unwrap();
+        let exec = match &svc.exec {
+            Some(a) => a.clone(),
+            None => {
+                error!(
+                    "Failed to execute service {}: no 'exec' specified!",
+                    svc.prompt
+                );
+                svc.failure = Some("no 'exec' specified".to_string());
+                svc.retry = false;
+                svc.set_phase(Phase::Idle);
+                return;
+            }
+        };
+        let mut env = svc.env.clone();
+        if let (true, Some(path)) = (svc.ready_notify, &self.notify_path) {
+            env.set("NOTIFY_SOCKET", path);
+            if let Some(t) = svc.watchdog_timeout {
+                env.set("WATCHDOG_USEC", &(t * 1000).to_string());
+            }
+        }
+        svc.status_text.clear();
+        let logs = &self.logs;
+        let log = svc.log.map(|i| &logs[i]);
+        let ctx = ExecCtx {
+            log,
+            attrs: Some(&svc.attrs),
+            cgroup: svc.cgroup.as_ref(),
+            sandbox: Some(&svc.sandbox),
+        };
+        let pid = match asystem(&svc.user, &exec, &env, ctx) {
+            Some(a) => a,
+            None => {
+                error!("Failed to execute service {}!", svc.prompt);
+                svc.failure = Some(format!("failed to execute \"{}\"", exec));
+                svc.set_phase(Phase::Idle);
+                return;
+            }
+        };
+        if svc.pid_file.is_some() {
+            svc.set_phase(Phase::Launching);
+            self.children.insert(pid, (id.to_string(), Role::Launcher));
+            self.arm(id, TimerKind::PidFile, 100);
+        } else {
+            svc.pid = pid;
+            self.children.insert(pid, (id.to_string(), Role::Main));
+            self.main_started(id);
+        }
+    }
+    fn main_started(&mut self, id: &str) {
+        let notify = self.notify_path.is_some();
+        let svc = self.services.get_mut(id).unwrap();
+        svc.pending_exit = None;
+        if svc.ready_notify && notify {
+            // Only a service that got NOTIFY_SOCKET can ping the watchdog.
+            if let Some(t) = svc.watchdog_timeout {
+                svc.last_ping = Instant::now();
+                self.arm(id, TimerKind::Watchdog, t);
+            }
+            let svc = self.services.get_mut(id).unwrap();
+            // Wait for READY=1; ready_timeout, if any, is how long we wait.
+            svc.set_phase(Phase::Readying);
+            if let Some(t) = svc.ready_timeout.filter(|t| *t > 0) {
+                self.arm(id, TimerKind::Ready, t);
+            }
+            return;
+        }
+        match svc.ready_timeout {
+            Some(t) => {
+                svc.set_phase(Phase::Readying);
+                self.arm(id, TimerKind::Ready, t);
+            }
+            None => self.running(id),
+        }
+    }
+    fn running(&mut self, id: &str) {
+        let svc = self.services.get_mut(id).unwrap();
+        svc.failure = None;
+        svc.set_phase(Phase::Running);
+        info!("Starting service {}({})...", svc.prompt, svc.desc);
+        self.poll_deps();
+    }
+    fn stop(&mut self, id: &str) {
+        let svc = match self.services.get_mut(id) {
+            Some(a) => a,
+            None => return,
+        };
+        svc.retry = false;
+        svc.restarts.clear();
+        svc.restarting = false;
+        match svc.phase {
+            Phase::WaitDeps | Phase::PreExec | Phase::Backoff => svc.set_phase(Phase::Idle),
+            _ if svc.is_up() => {
+                let pre_stop = svc.pre_stop.clone();
+                self.pre_stop(id, pre_stop);
+            }
+            _ => (),
+        }
+    }
+    fn restart(&mut self, id: &str) {
+        let svc = match self.services.get_mut(id) {
+            Some(a) => a,
+            None => return,
+        };
+        if !svc.is_up() {
+            return;
+        }
+        svc.retry = true;
+        svc.restarts.clear();
+        svc.restarting = true;
+        let pre_restart = svc.pre_restart.clone();
+        self.pre_stop(id, pre_restart);
+    }
+    fn pre_stop(&mut self, id: &str, cmd: Option<Cmdline>) {
+        let svc = self.services.get_mut(id).unwrap();
+        let logs = &self.logs;
+        let log = svc.log.map(|i| &logs[i]);
+        if let Some(cmd) = cmd {
+            if let Some(pid) = asystem(
+                &svc.action_user,
+                &cmd,
+                &svc.env,
+                ExecCtx {
+                    log,
+                    attrs: Some(&svc.attrs),
+                    ..Default::default()
+                },
+            ) {
+                svc.set_phase(Phase::PreStop);
+                self.children.insert(pid, (id.to_string(), Role::PreStop));
+                return;
+            }
+        }
+        self.issue_stop(id);
+    }
+    fn issue_stop(&mut self, id: &str) {
+        let svc = self.services.get_mut(id).unwrap();
+        let way = if svc.restarting {
+            svc.restart_way.clone()
+        } else {
+            svc.stop_way.clone()
+        };
+        svc.set_phase(Phase::Stopping);
+        if svc.pid == 0 {
+            if !svc.has_leftovers() {
+                self.after_stop(id);
+                return;
+            }
+            // Only leftovers of an exited main process; a stop command
+            // would have no ${PID} to work with.
+            svc.signal(SIGTERM);
+        } else {
+            let logs = &self.logs;
+            let ctx = ExecCtx {
+                log: svc.log.map(|i| &logs[i]),
+                attrs: Some(&svc.attrs),
+                cgroup: svc.cgroup.as_ref(),
+                ..Default::default()
+            };
+            if let Some(pid) = svc_stop(&svc.action_user, &svc.env, ctx, &way, svc.pid) {
+                self.children.insert(pid, (id.to_string(), Role::StopCmd));
+            }
+        }
+        let kill_timeout = svc.kill_timeout;
+        self.arm(id, TimerKind::Kill, kill_timeout);
+    }
+    fn after_stop(&mut self, id: &str) {
+        let svc = self.services.get_mut(id).unwrap();
+        svc.pid = 0;
+        svc.timer_gen += 1;
+        if !svc.restarting || svc.cleanup_on_restart {
+            if let Some(cleanup) = svc.cleanup.clone() {
+                let logs = &self.logs;
+                let log = svc.log.map(|i| &logs[i]);
+                match asystem(
+                    &svc.action_user,
+                    &cleanup,
+                    &svc.env,
+                    ExecCtx {
+                        log,
+                        attrs: Some(&svc.attrs),
+                        ..Default::default()
+                    },
+                ) {
+                    Some(pid) => {
+                        svc.set_phase(Phase::Cleanup);
+                        self.children.insert(pid, (id.to_string(), Role::Cleanup));
+                        return;
+                    }
+                    None => error!(
+                        "Failed to cleanup service {}: failed to run cleanup command \"{}\"!",
+                        id, cleanup
+                    ),
+                }
+            }
+        }
+        self.stopped(id);
+    }
+    fn stopped(&mut self, id: &str) {
+        let svc = self.services.get_mut(id).unwrap();
+        if svc.restarting {
+            svc.restarting = false;
+            self.apply_pending(id);
+            self.spawn_main(id);
+        } else {
+            svc.set_phase(Phase::Idle);
+        }
+    }
+    fn main_exited(&mut self, id: &str, status: c_int) {
+        let svc = self.services.get_mut(id).unwrap();
+        // Processes the main one left behind keep the service up.
+        if svc.has_leftovers() {
+            svc.pid = 0;
+            svc.pending_exit = Some(status);
+            return;
+        }
+        self.service_exited(id, status);
+    }
+    fn cgroup_changed(&mut self, wd: c_int) {
+        let id = match self.watches.get(&wd) {
+            Some(a) => a.clone(),
+            None => return,
+        };
+        let svc = self.services.get_mut(&id).unwrap();
+        if svc.pid != 0 || svc.pending_exit.is_none() || svc.has_leftovers() {
+            return;
+        }
+        let status = svc.pending_exit.take().unwrap();
+        self.service_exited(&id, status);
+    }
+    fn service_exited(&mut self, id: &str, status: c_int) {
+        let svc = self.services.get_mut(id).unwrap();
+        svc.pending_exit = None;
+        let exit = decode_status(status);
+        svc.last_exit = Some(exit);
+        if svc.phase == Phase::Stopping {
+            self.after_stop(id);
+            return;
+        }
+        svc.pid = 0;
+        svc.timer_gen += 1;
+        svc.restarting = false;
+        if !svc.retry {
+            svc.set_phase(Phase::Idle);
+            return;
+        }
+        let clean = svc.is_clean(exit);
+        if !svc.wants_restart(exit) {
+            if clean {
+                info!("Service {} {}.", svc.prompt, exit);
+            } else {
+                error!("Service {} {}!", svc.prompt, exit);
+                svc.failure.get_or_insert_with(|| exit.to_string());
+            }
+            svc.set_phase(Phase::Idle);
+            return;
+        }
+        // Give up once the service has been restarted retry_time times
+        // within the last retry_window milliseconds.
+        let now = Instant::now();
+        let window = Duration::from_millis(svc.retry_window);
+        svc.restarts.retain(|t| now.duration_since(*t) < window);
+        if svc.restarts.len() as i64 >= svc.retry_time {
+            error!("Service {} restarted too many times!", svc.prompt);
+            svc.failure
+                .get_or_insert_with(|| format!("restarted too many times, last {}", exit));
+            svc.retry = false;
+            svc.set_phase(Phase::Idle);
+            self.poll_deps();
+            return;
+        }
+        if clean {
+            warn!(
+                "Service {} {}, but not returning an error. restarting...",
+                svc.prompt, exit
+            );
+        } else {
+            error!(
+                "Service {} {} unexpectedly! restarting...",
+                svc.prompt, exit
+            );
+        }
+        // The delay doubles with every restart still inside the window.
+        let delay = svc
+            .restart_delay
+            .saturating_mul(1 << svc.restarts.len().min(32))
+            .min(svc.restart_delay_max);
+        svc.restarts.push(now);
+        svc.set_phase(Phase::Backoff);
+        self.arm(id, TimerKind::Restart, delay);
+    }
+    fn child_exited(&mut self, pid: pid_t, status: c_int) {
+        let (id, role) = match self.children.remove(&pid) {
+            Some(a) => a,
+            None => return,
+        };
+        let phase = match self.services.get(&id) {
+            Some(svc) => svc.phase,
+            None => return,
+        };
+        match role {
+            Role::Main => self.main_exited(&id, status),
+            Role::Launcher if phase == Phase::Launching && status != 0 => {
+                self.main_exited(&id, status)
+            }
+            Role::PreExec if phase == Phase::PreExec => self.spawn_main(&id),
+            Role::PreStop if phase == Phase::PreStop => self.issue_stop(&id),
+            Role::Cleanup if phase == Phase::Cleanup => self.stopped(&id),
+            _ => (),
+        }
+    }
+    fn timer_fired(&mut self, id: &str, kind: TimerKind) {
+        let svc = self.services.get_mut(id).unwrap();
+        match kind {
+            TimerKind::Ready if svc.phase == Phase::Readying && svc.ready_notify => {
+                error!("Service {} did not report readiness in time!", svc.prompt);
+                svc.failure = Some("did not report readiness in time".to_string());
+                svc.signal(SIGKILL);
+            }
+            TimerKind::Ready if svc.phase == Phase::Readying => self.running(id),
+            TimerKind::Restart if svc.phase == Phase::Backoff => self.begin_exec(id),
+            TimerKind::Watchdog if svc.is_up() => {
+                let timeout = Duration::from_millis(svc.watchdog_timeout.unwrap());
+                let elapsed = svc.last_ping.elapsed();
+                if elapsed < timeout {
+                    self.arm(
+                        id,
+                        TimerKind::Watchdog,
+                        (timeout - elapsed).as_millis() as u64,
+                    );
+                    return;
+                }
+                error!("Watchdog of service {} expired: aborting it!", svc.prompt);
+                svc.signal(SIGABRT);
+            }
+            TimerKind::Kill if svc.phase == Phase::Stopping => {
+                error!(
+                    "Failed to stop service {} normally: Attempting to kill service!",
+                    id
+                );
+                match &svc.cgroup {
+                    Some(cgroup) => cgroup.kill(svc.pid),
+                    None => {
+                        send_signal(svc.pid, SIGKILL);
+                    }
+                }
+            }
+            TimerKind::PidFile if svc.phase == Phase::Launching => {
+                let pid_file = svc.pid_file.clone().unwrap();
+                if !Path::new(&pid_file).exists() {
+                    self.arm(id, TimerKind::PidFile, 100);
+                    return;
+                }
+                let pid = fs::read_to_string(&pid_file)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<pid_t>().ok());
+                match pid {
+                    Some(pid) => {
+                        svc.pid = pid;
+                        self.children.insert(pid, (id.to_string(), Role::Main));
+                        self.main_started(id);
+                    }
+                    None => {
+                        error!("PID file format error!");
+                        svc.failure = Some(format!("PID file {} is malformed", pid_file));
+                        svc.set_phase(Phase::Idle);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    fn arm(&mut self, id: &str, kind: TimerKind, ms: u64) {
+        let gen = self.services[id].timer_gen;
+        self.timer_seq += 1;
+        let when = Instant::now() + Duration::from_millis(ms);
+        self.timers
+            .insert((when, self.timer_seq), (id.to_string(), kind, gen));
+        self.rearm_timerfd();
+    }
+    fn rearm_timerfd(&self) {
+        let mut spec: itimerspec = unsafe { mem::zeroed() };
+        if let Some((when, _)) = self.timers.keys().next() {
+            let dur = when.saturating_duration_since(Instant::now());
+            let dur = dur.max(Duration::from_nanos(1));
+            spec.it_value = timespec {
+                tv_sec: dur.as_secs() as _,
+                tv_nsec: dur.subsec_nanos() as _,
+            };
+        }
+        unsafe {
+            libc::timerfd_settime(self.timerfd, 0, &spec, std::ptr::null_mut());
+        }
+    }
+    fn run_timers(&mut self) {
+        let now = Instant::now();
+        while let Some(key) = self.timers.keys().next().cloned() {
+            if key.0 > now {
+                break;
+            }
+            let (id, kind, gen) = self.timers.remove(&key).unwrap();
+            if self.services.get(&id).map(|s| s.timer_gen) == Some(gen) {
+                self.timer_fired(&id, kind);
+            }
+        }
+        self.rearm_timerfd();
+    }
+    // Collect every exited child. Exits of processes we track are routed
+    // to their service, those of airupd's own children to whoever waits
+    // for them; anything else is an orphan that was reparented to us and
+    // is just reaped.
+    fn reap(&mut self) {
+        loop {
+            let mut status: c_int = 0;
+            let pid = unsafe { waitpid(-1, &mut status, WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+            if self.children.contains_key(&pid) {
+                self.child_exited(pid, status);
+                continue;
+            }
+            let mut own = OWN_CHILDREN.lock().unwrap();
+            match own.get(&pid) {
+                Some(OwnChild::Detached) => {
+                    own.remove(&pid);
+                }
+                Some(_) => {
+                    own.insert(pid, OwnChild::Exited(status));
+                    OWN_CHILD_EXITED.notify_all();
+                }
+                None => {
+                    debug!("Reaped orphan process {}.", pid);
+                    self.orphans_reaped += 1;
+                }
+            }
+        }
+        self.poll_deps();
+    }
+    // Handle an sd_notify(3) message. Only READY, STATUS, MAINPID and
+    // WATCHDOG are understood; anything else is ignored.
+    fn notified(&mut self, pid: pid_t, msg: &str) {
+        let id = match self.owner_of(pid) {
+            Some(a) => a,
+            None => return,
+        };
+        let vars: HashMap<&str, &str> = msg.lines().filter_map(|l| l.split_once('=')).collect();
+        if let Some(pid) = vars.get("MAINPID").and_then(|a| a.parse::<pid_t>().ok()) {
+            let svc = self.services.get_mut(&id).unwrap();
+            if pid > 1 && pid != svc.pid && svc.is_up() {
+                if svc.owns(pid) {
+                    // The old main process no longer decides the service's fate.
+                    if let Some(child) = self.children.get_mut(&svc.pid) {
+                        child.1 = Role::Launcher;
+                    }
+                    svc.pid = pid;
+                    self.children.insert(pid, (id.clone(), Role::Main));
+                } else {
+                    warn!(
+                        "Service {} sent MAINPID={} of a process not its own: ignoring it!",
+                        svc.prompt, pid
+                    );
+                }
+            }
+        }
+        let svc = self.services.get_mut(&id).unwrap();
+        if let Some(text) = vars.get("STATUS") {
+            svc.status_text = text.to_string();
+        }
+        if vars.get("WATCHDOG") == Some(&"1") {
+            svc.last_ping = Instant::now();
+        }
+        if vars.get("READY") == Some(&"1") && svc.ready_notify && svc.phase == Phase::Readying {
+            svc.timer_gen += 1;
+            if let Some(t) = svc.watchdog_timeout {
+                self.arm(&id, TimerKind::Watchdog, t);
+            }
+            self.running(&id);
+        }
+    }
+    // The service a process belongs to: the one whose main process (or
+    // pid_file launcher) is the process itself or one of its ancestors.
+    fn owner_of(&self, pid: pid_t) -> Option<String> {
+        let mut pid = pid;
+        while pid > 1 {
+            if let Some((id, Role::Main)) | Some((id, Role::Launcher)) = self.children.get(&pid) {
+                return Some(id.clone());
+            }
+            pid = parent_of(pid)?;
+        }
+        None
+    }
+    // Give a service that takes its io a log pipe, the first time it runs.
+    fn open_log(&mut self, id: &str) {
+        let svc = self.services.get_mut(id).unwrap();
+        if !svc.take_io || svc.log.is_some() {
+            return;
+        }
+        let log = match ServiceLog::open(id) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("Failed to capture output of service {}({})!", id, e);
+                return;
+            }
+        };
+        let token = TOKEN_LOG + self.logs.len() as u64;
+        if let Err(e) = epoll_add(self.epfd, log.read_fd(), token) {
+            warn!("Failed to capture output of service {}({})!", id, e);
+            return;
+        }
+        svc.log = Some(self.logs.len());
+        self.logs.push(log);
+    }
+    // Give the service its own cgroup, the first time it runs. Without a
+    // watch on cgroup.events we could not tell when it empties, so such a
+    // service goes without one.
+    fn open_cgroup(&mut self, id: &str) {
+        let hierarchy = match &self.cgroups {
+            Some(a) => a,
+            None => return,
+        };
+        let svc = self.services.get_mut(id).unwrap();
+        if svc.cgroup.is_some() {
+            return;
+        }
+        let cgroup = match hierarchy.create(id) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("Failed to create cgroup of service {}({})!", id, e);
+                return;
+            }
+        };
+        let events = CString::new(cgroup.events().as_os_str().as_bytes()).unwrap();
+        let wd = unsafe { libc::inotify_add_watch(self.inotify, events.as_ptr(), IN_MODIFY) };
+        if wd < 0 {
+            warn!(
+                "Failed to watch cgroup of service {}({})!",
+                id,
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        self.watches.insert(wd, id.to_string());
+        svc.cgroup = Some(cgroup);
+        svc.set_limits();
+    }
+    // Remove the cgroups of services that are down once they are empty, so
+    // that stopped services leave no directories behind. open_cgroup makes
+    // a new one when the service starts again.
+    fn release_cgroups(&mut self) {
+        let inotify = self.inotify;
+        let watches = &mut self.watches;
+        for svc in self.services.values_mut() {
+            let cgroup = match &svc.cgroup {
+                Some(a) if svc.phase == Phase::Idle && !a.populated() => a,
+                _ => continue,
+            };
+            // Left behind, it is taken over again by the next start.
+            if let Err(e) = cgroup.remove() {
+                warn!("Failed to remove cgroup of service {}({})!", svc.id, e);
+            }
+            let id = &svc.id;
+            watches.retain(|wd, watched| {
+                if watched != id {
+                    return true;
+                }
+                unsafe { libc::inotify_rm_watch(inotify, *wd) };
+                false
+            });
+            svc.cgroup = None;
+        }
+    }
+    // A definition that changed while the service was up takes over once
+    // it starts again.
+    fn apply_pending(&mut self, id: &str) {
+        let svc = self.services.get_mut(id).unwrap();
+        if let Some(new) = svc.pending.take() {
+            svc.redefine(*new);
+            svc.set_limits();
+        }
+    }
+    fn drain_log(&mut self, index: usize) {
+        if let Some(log) = self.logs.get_mut(index) {
+            log.drain(&self.log_conf);
+        }
+    }
+    // Answer the svcrun_wait and svcrun_all callers whose service has
+    // settled.
+    fn notify_waiters(&mut self) {
+        let services = &self.services;
+        self.waiters.retain(|id, waiters| {
+            let ok = match services.get(id).map(|s| s.phase) {
+                Some(Phase::Running) => true,
+                Some(Phase::Idle) | None => false,
+                _ => return true,
+            };
+            for waiter in waiters.drain(..) {
+                waiter.send(ok).ok();
+            }
+            false
+        });
+    }
+    fn handle(&mut self, cmd: SupCmd) {
+        match cmd {
+            SupCmd::Load(path, waiter) => match self.load(&path) {
+                Some(id) => {
+                    let started = *CURRENT_STAGE.read().unwrap() != Stage::Shutdown
+                        && self.start(std::slice::from_ref(&id)).is_ok();
+                    if let Some(waiter) = waiter {
+                        if started {
+                            self.waiters.entry(id).or_default().push(waiter);
+                        } else {
+                            waiter.send(false).ok();
+                        }
+                    }
+                }
+                None => {
+                    if let Some(waiter) = waiter {
+                        waiter.send(false).ok();
+                    }
+                }
+            },
+            SupCmd::LoadAll(paths, waiter, ready) => {
+                let ids: Vec<String> = paths.iter().filter_map(|p| self.load(p)).collect();
+                let started =
+                    *CURRENT_STAGE.read().unwrap() != Stage::Shutdown && self.start(&ids).is_ok();
+                if !started {
+                    waiter.send(None).ok();
+                    return;
+                }
+                for id in ids.iter() {
+                    self.waiters
+                        .entry(id.clone())
+                        .or_default()
+                        .push(ready.clone());
+                }
+                waiter.send(Some(ids.len())).ok();
+            }
+            SupCmd::Stop(id) => self.stop(&id),
+            SupCmd::Restart(id) => self.restart(&id),
+            SupCmd::StopAll => {
+                let ids: Vec<String> = self.services.keys().cloned().collect();
+                for id in ids {
+                    self.stop(&id);
+                }
+            }
+        }
+    }
+    pub fn is_known(&self, id: &str) -> bool {
+        self.services.contains_key(id)
+    }
+    pub fn list(&self) -> Vec<(String, pid_t)> {
+        let mut list: Vec<(String, pid_t)> = self
+            .services
+            .values()
+            .map(|s| (s.id.clone(), s.pid))
+            .collect();
+        list.sort();
+        list
+    }
+    pub fn pid_of(&self, id: &str) -> pid_t {
+        self.services.get(id).map(|s| s.pid).unwrap_or(0)
+    }
+    pub fn last_exit(&self, id: &str) -> Option<ExitStatus> {
+        self.services.get(id).and_then(|s| s.last_exit)
+    }
+    pub fn sandbox(&self, id: &str) -> Vec<String> {
+        self.services
+            .get(id)
+            .map(|s| s.sandbox.summary().to_vec())
+            .unwrap_or_default()
+    }
+    pub fn failure(&self, id: &str) -> Option<String> {
+        self.services.get(id).and_then(|s| s.failure.clone())
+    }
+    pub fn status_text(&self, id: &str) -> Option<String> {
+        self.services
+            .get(id)
+            .map(|s| s.status_text.clone())
+            .filter(|s| !s.is_empty())
+    }
+    // None if the service is unknown; a service without captured output
+    // simply has no lines.
+    pub fn logs(&self, id: &str, since: Option<u64>, count: usize) -> Option<(Vec<String>, u64)> {
+        let svc = self.services.get(id)?;
+        Some(match svc.log {
+            Some(i) => self.logs[i].lines(since, count),
+            None => (Vec::new(), 0),
+        })
+    }
+    pub fn orphans_reaped(&self) -> u64 {
+        self.orphans_reaped
+    }
+    pub fn airup_dir(&self) -> &'static str {
+        self.airup_dir
+    }
+    pub fn needs_restart(&self, id: &str) -> bool {
+        self.services
+            .get(id)
+            .map(|s| s.pending.is_some())
+            .unwrap_or(false)
+    }
+    pub fn request(&mut self, cmd: SupCmd) {
+        self.handle(cmd);
+    }
+    // Where the definition of a service not loaded yet lives: airup_home/svc,
+    // else a milestone directory, the target milestone's first.
+    pub fn find_service(&self, id: &str) -> Option<PathBuf> {
+        if id.is_empty() || id.contains('/') {
+            return None;
+        }
+        let svc = Path::new(self.airup_dir)
+            .join("svc")
+            .join(format!("{}.svc", id));
+        if svc.exists() {
+            return Some(svc);
+        }
+        let milestone = env::var("AIRUP_TARGET_MILESTONE").unwrap_or_default();
+        let mut dirs: Vec<PathBuf> = fs::read_dir(Path::new(self.airup_dir).join("milestones"))
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect();
+        dirs.sort();
+        milestone_services(self.airup_dir, &milestone)
+            .into_iter()
+            .chain(dirs.iter().flat_map(|d| svc_files(d)))
+            .find(|p| svcid_detect(&p.to_string_lossy()) == id)
+    }
+    // Start a service by name, loading it and what it depends on first if
+    // it is not supervised yet. Only refusals are reported; how the start
+    // itself goes shows in its status.
+    pub fn start_service(&mut self, id: &str) -> Result<(), String> {
+        if *CURRENT_STAGE.read().unwrap() == Stage::Shutdown {
+            return Err("the system is shutting down".to_string());
+        }
+        let id = if self.services.contains_key(id) {
+            id.to_string()
+        } else {
+            let path = self
+                .find_service(id)
+                .ok_or_else(|| format!("service {} does not exist", id))?;
+            self.try_load(&path)
+                .map_err(|e| format!("cannot load {}", e))?
+        };
+        self.start(std::slice::from_ref(&id))
+            .map_err(|e| format!("cannot start {}: {}", id, e))
+    }
+    // Stop every service that neither `milestone` nor the milestones it
+    // depends on need, directly or as a dependency. Nothing is stopped if
+    // one of its services cannot be loaded.
+    pub fn isolate(&mut self, milestone: &str) -> Result<(), String> {
+        let mut queue = Vec::new();
+        for file in milestone_services(self.airup_dir, milestone) {
+            queue.push(
+                self.try_load(&file)
+                    .map_err(|e| format!("cannot load {}", e))?,
+            );
+        }
+        let mut keep: Vec<String> = Vec::new();
+        while let Some(id) = queue.pop() {
+            if keep.contains(&id) {
+                continue;
+            }
+            for dep in self.services[&id].deps.clone() {
+                if let Some(dep) = self.load_dep(&dep) {
+                    queue.push(dep);
+                }
+            }
+            keep.push(id);
+        }
+        let mut ids: Vec<String> = self
+            .services
+            .keys()
+            .filter(|id| !keep.contains(id))
+            .cloned()
+            .collect();
+        ids.sort();
+        for id in ids {
+            self.stop(&id);
+        }
+        Ok(())
+    }
+    // Read every loaded service again. An idle one takes its new
+    // definition as a whole; one that is up takes the LIVE_KEYS at once and
+    // the rest when it next starts. Services the target milestone gained
+    // are started.
+    pub fn reload(&mut self) -> Reloaded {
+        let mut reloaded = Reloaded::default();
+        let mut ids: Vec<String> = self.services.keys().cloned().collect();
+        ids.sort();
+        for id in ids {
+            let svc = &self.services[&id];
+            let source = svc.source.clone();
+            let mut report = Report::default();
+            let read = config::read_service(&source, &mut report);
+            for warning in &report.warnings {
+                warn!("{}", warning);
+            }
+            let new = match (read, report.errors.first()) {
+                (Some((_, defn)), None) if &defn == svc.latest_defn() => continue,
+                (Some((cfg, defn)), None) => Service::new(&id, &source, cfg, defn),
+                (_, e) => Err(e
+                    .cloned()
+                    .unwrap_or_else(|| "invalid configuration".to_string())),
+            };
+            let new = match new {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("Failed to reload service {}: {}!", id, e);
+                    reloaded.failed.push(id);
+                    continue;
+                }
+            };
+            let svc = self.services.get_mut(&id).unwrap();
+            if svc.phase == Phase::Idle {
+                svc.redefine(new);
+            } else {
+                svc.update(&new);
+                let empty = Map::new();
+                let old = svc.defn.as_table().unwrap_or(&empty);
+                let cur = new.defn.as_table().unwrap_or(&empty);
+                let restart = old
+                    .keys()
+                    .chain(cur.keys())
+                    .filter(|k| !LIVE_KEYS.contains(&k.as_str()))
+                    .any(|k| old.get(k) != cur.get(k));
+                if restart {
+                    svc.pending = Some(Box::new(new));
+                    reloaded.needs_restart.push(id.clone());
+                } else {
+                    svc.defn = new.defn;
+                    svc.pending = None;
+                }
+            }
+            svc.set_limits();
+            info!("Reloaded service {}.", svc.prompt);
+            reloaded.changed.push(id);
+        }
+        let booted = matches!(*CURRENT_STAGE.read().unwrap(), Stage::Milestones(_));
+        if booted {
+            let milestone = env::var("AIRUP_TARGET_MILESTONE").unwrap_or_default();
+            let mut added = Vec::new();
+            for file in milestone_services(self.airup_dir, &milestone) {
+                let id = svcid_detect(&file.to_string_lossy());
+                if self.services.contains_key(&id) {
+                    continue;
+                }
+                match self.load(&file) {
+                    Some(id) => added.push(id),
+                    None => reloaded.failed.push(id),
+                }
+            }
+            if !added.is_empty() {
+                if self.start(&added).is_ok() {
+                    reloaded.added = added;
+                } else {
+                    reloaded.failed.append(&mut added);
+                }
+            }
+        }
+        reloaded
+    }
+}
+// The services a milestone starts, with those of the milestones it
+// depends on.
+fn milestone_services(airup_dir: &str, milestone: &str) -> Vec<PathBuf> {
+    let dir = Path::new(airup_dir).join("milestones");
+    let mut names = vec![milestone.to_string()];
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < names.len() {
+        let mdir = dir.join(&names[i]);
+        i += 1;
+        let mtpath = mdir.join("milestone.toml");
+        for dep in config::read_milestone(&mtpath, &mut Report::default()).dependencies {
+            if !names.contains(&dep) {
+                names.push(dep);
+            }
+        }
+        files.append(&mut svc_files(&mdir));
+    }
+    files
+}
+fn svc_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().map(|e| e == "svc").unwrap_or(false))
+        .collect();
+    files.sort();
+    files
+}
+// A stop signal goes to the whole cgroup, if the service has one; a stop
+// command runs outside of it.
+fn svc_stop(
+    action_user: &Identity,
+    env: &Environ,
+    ctx: ExecCtx,
+    stop_way: &StopWay,
+    svc_pid: pid_t,
+) -> Option<pid_t> {
+    match stop_way {
+        StopWay::Signal(sig) => {
+            match ctx.cgroup {
+                Some(cgroup) => cgroup.signal(*sig, svc_pid),
+                None => {
+                    send_signal(svc_pid, *sig);
+                }
+            }
+            None
+        }
+        StopWay::Command(cmd) => asystem(
+            action_user,
+            &cmd.replace("${PID}", &svc_pid.to_string()),
+            env,
+            ExecCtx {
+                cgroup: None,
+                sandbox: None,
+                ..ctx
+            },
+        ),
+    }
+}
+
+fn epoll_add(epfd: RawFd, fd: RawFd, token: u64) -> io::Result<()> {
+    let mut ev = epoll_event {
+        events: EPOLLIN as u32,
+        u64: token,
+    };
+    if unsafe { libc::epoll_ctl(epfd, EPOLL_CTL_ADD, fd, &mut ev) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+fn decode_status(status: c_int) -> ExitStatus {
+    if libc::WIFSIGNALED(status) {
+        ExitStatus::Killed {
+            signal: libc::WTERMSIG(status),
+            core_dumped: libc::WCOREDUMP(status),
+        }
+    } else {
+        ExitStatus::Exited {
+            code: libc::WEXITSTATUS(status),
+        }
+    }
+}
+fn parent_of(pid: pid_t) -> Option<pid_t> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces, so start after its parenthesis.
+    stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+fn descends_from(pid: pid_t, ancestor: pid_t) -> bool {
+    let mut pid = pid;
+    while pid > 1 {
+        if pid == ancestor {
+            return true;
+        }
+        pid = match parent_of(pid) {
+            Some(a) => a,
+            None => return false,
+        };
+    }
+    false
+}
+// Services send sd_notify(3) datagrams to this socket; with SO_PASSCRED
+// the kernel attaches the sender's pid to each of them.
+fn notify_open(path: &Path) -> io::Result<RawFd> {
+    let mut addr: sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = AF_UNIX as sa_family_t;
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "notify socket path is too long",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as c_char;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).ok();
+    }
+    fs::remove_file(path).ok();
+    let fd = unsafe { libc::socket(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC | SOCK_NONBLOCK, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let one: c_int = 1;
+    let failed = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const sockaddr_un as *const sockaddr,
+            mem::size_of::<sockaddr_un>() as socklen_t,
+        ) < 0
+            || libc::setsockopt(
+                fd,
+                SOL_SOCKET,
+                SO_PASSCRED,
+                &one as *const c_int as *const c_void,
+                mem::size_of::<c_int>() as socklen_t,
+            ) < 0
+    };
+    if failed {
+        let err = io::Error::last_os_error();
+        unsafe {
+            libc::close(fd);
+        }
+        return Err(err);
+    }
+    // Services running as other users must be able to notify us too.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(fd)
+}
+fn notify_recv(fd: RawFd) -> io::Result<(pid_t, String)> {
+    let mut buf = [0u8; 4096];
+    let mut cbuf = [0u64; 8];
+    let mut iov = iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cbuf.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(&cbuf) as _;
+    let n = unsafe { libc::recvmsg(fd, &mut msg, MSG_DONTWAIT) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut pid = 0;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_CREDENTIALS {
+                pid = (*(libc::CMSG_DATA(cmsg) as *const ucred)).pid;
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((pid, String::from_utf8_lossy(&buf[..n as usize]).to_string()))
+}
+// Next to the control socket, unless that is not a local one.
+fn notify_path() -> PathBuf {
+    let address = libairup::ipc::address();
+    let dir = address
+        .strip_prefix("ipc://")
+        .and_then(|a| Path::new(a).parent())
+        .unwrap_or_else(|| Path::new("/run/airup"));
+    dir.join("notify.sock")
+}
+// Watch descriptors of the inotify events waiting on `fd`.
+fn inotify_recv(fd: RawFd) -> Vec<c_int> {
+    let mut wds = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        let mut off = 0;
+        while off + mem::size_of::<inotify_event>() <= n as usize {
+            let ev =
+                unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const inotify_event) };
+            wds.push(ev.wd);
+            off += mem::size_of::<inotify_event>() + ev.len as usize;
+        }
+    }
+    wds
+}
+fn drain(fd: RawFd, size: usize) {
+    let mut buf = [0u8; 128];
+    while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, size) } > 0 {}
+}
+// The supervisor event loop. SIGCHLD arrives through a signalfd, every
+// service timer is multiplexed onto one timerfd, and IPC requests are
+// picked up from the socket's receive descriptor.
+pub fn supervisor_main(
+    airup_dir: &'static str,
+    providers: HashMap<String, String>,
+    log_conf: LogConfig,
+    server: Option<ipc::Server>,
+) -> io::Result<()> {
+    let epfd = unsafe { libc::epoll_create1(EPOLL_CLOEXEC) };
+    let set = sigchld_set();
+    let sigfd = unsafe { libc::signalfd(-1, &set, SFD_NONBLOCK | SFD_CLOEXEC) };
+    let timerfd = unsafe { libc::timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC) };
+    if epfd < 0 || sigfd < 0 || timerfd < 0 || *SUP_WAKE < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Outside PID 1 (quickdbg), orphans only come back to us as a subreaper.
+    if unsafe { libc::getpid() } != 1 {
+        unsafe {
+            libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0);
+        }
+    }
+    epoll_add(epfd, sigfd, TOKEN_SIGNAL)?;
+    epoll_add(epfd, timerfd, TOKEN_TIMER)?;
+    epoll_add(epfd, *SUP_WAKE, TOKEN_WAKE)?;
+    if let Some(server) = &server {
+        match server.recv_fd() {
+            Some(fd) => epoll_add(epfd, fd, TOKEN_IPC)?,
+            None => error!("Failed to poll the IPC socket: running in RO mode!"),
+        }
+    }
+    let notify_path = notify_path();
+    let notifyfd = match notify_open(&notify_path) {
+        Ok(fd) => {
+            epoll_add(epfd, fd, TOKEN_NOTIFY)?;
+            Some(fd)
+        }
+        Err(e) => {
+            error!(
+                "Failed to open notify socket {}({}): ready = \"notify\" is unavailable!",
+                notify_path.to_string_lossy(),
+                e
+            );
+            None
+        }
+    };
+    // Each service gets a cgroup; cgroup.events tells us when it empties.
+    let inotify = unsafe { libc::inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+    let cgroups = if inotify < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Hierarchy::open()
+    };
+    let cgroups = match cgroups {
+        Ok(a) => {
+            epoll_add(epfd, inotify, TOKEN_CGROUP)?;
+            Some(a)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to set up cgroups({}): services are not contained!",
+                e
+            );
+            None
+        }
+    };
+    let mut sup = Supervisor {
+        airup_dir,
+        providers,
+        services: HashMap::new(),
+        children: HashMap::new(),
+        timers: BTreeMap::new(),
+        timer_seq: 0,
+        timerfd,
+        orphans_reaped: 0,
+        waiters: HashMap::new(),
+        notify_path: notifyfd.map(|_| notify_path.to_string_lossy().to_string()),
+        epfd,
+        log_conf,
+        logs: Vec::new(),
+        cgroups,
+        inotify,
+        watches: HashMap::new(),
+    };
+    let mut events: Vec<epoll_event> = vec![epoll_event { events: 0, u64: 0 }; 16];
+    loop {
+        let n = unsafe { libc::epoll_wait(epfd, events.as_mut_ptr(), events.len() as c_int, -1) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(EINTR) {
+                continue;
+            }
+            return Err(err);
+        }
+        for ev in &events[..n as usize] {
+            let token = ev.u64;
+            match token {
+                TOKEN_SIGNAL => {
+                    drain(sigfd, mem::size_of::<signalfd_siginfo>());
+                    sup.reap();
+                }
+                TOKEN_TIMER => {
+                    drain(timerfd, 8);
+                    sup.run_timers();
+                }
+                TOKEN_WAKE => {
+                    drain(*SUP_WAKE, 8);
+                    let cmds: Vec<SupCmd> = SUP_QUEUE.lock().unwrap().drain(..).collect();
+                    for cmd in cmds {
+                        sup.handle(cmd);
+                    }
+                }
+                TOKEN_NOTIFY => {
+                    while let Ok((pid, msg)) = notify_recv(notifyfd.unwrap()) {
+                        sup.notified(pid, &msg);
+                    }
+                }
+                TOKEN_CGROUP => {
+                    for wd in inotify_recv(inotify) {
+                        sup.cgroup_changed(wd);
+                    }
+                }
+                _ if token >= TOKEN_LOG => sup.drain_log((token - TOKEN_LOG) as usize),
+                TOKEN_IPC => {
+                    if let Some(server) = &server {
+                        server.serve(&mut sup);
+                    }
+                }
+                _ => (),
+            }
+        }
+        // Timers and commands can take a dependency down as well.
+        sup.poll_deps();
+        sup.notify_waiters();
+        sup.release_cgroups();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(id: &str, cfg: ServiceConfig) -> Service {
+        Service::new(id, Path::new(id), cfg, Value::Table(Map::new())).unwrap()
+    }
+    fn supervisor(graph: &[(&str, &[&str])]) -> Supervisor {
+        let mut sup = Supervisor {
+            airup_dir: "/nonexistent",
+            providers: HashMap::new(),
+            services: HashMap::new(),
+            children: HashMap::new(),
+            timers: BTreeMap::new(),
+            timer_seq: 0,
+            timerfd: -1,
+            orphans_reaped: 0,
+            waiters: HashMap::new(),
+            notify_path: None,
+            epfd: -1,
+            log_conf: LogConfig {
+                dir: PathBuf::from("/nonexistent"),
+                max_size: 0,
+                rotate: 0,
+                lines: 0,
+            },
+            logs: Vec::new(),
+            cgroups: None,
+            inotify: -1,
+            watches: HashMap::new(),
+        };
+        for (id, deps) in graph {
+            let cfg = ServiceConfig {
+                dependencies: deps.iter().map(|a| a.to_string()).collect(),
+                ..Default::default()
+            };
+            sup.services.insert(id.to_string(), service(id, cfg));
+        }
+        sup
+    }
+    fn ids(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn plan_sorts_into_waves() {
+        let mut sup = supervisor(&[("a", &["b", "c"]), ("b", &["c"]), ("c", &[]), ("d", &[])]);
+        let waves = sup.plan(&ids(&["a", "d"])).unwrap();
+        assert_eq!(waves, vec![ids(&["c", "d"]), ids(&["b"]), ids(&["a"])]);
+    }
+    #[test]
+    fn plan_skips_services_already_up() {
+        let mut sup = supervisor(&[("a", &["b"]), ("b", &[])]);
+        sup.services.get_mut("b").unwrap().phase = Phase::Running;
+        assert_eq!(sup.plan(&ids(&["a"])).unwrap(), vec![ids(&["a"])]);
+    }
+    #[test]
+    fn plan_reports_cycles() {
+        let mut sup = supervisor(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &["a"])]);
+        assert_eq!(
+            sup.plan(&ids(&["d"])).unwrap_err(),
+            "dependency cycle (a -> b -> c -> a)"
+        );
+    }
+    #[test]
+    fn plan_reports_missing_dependencies() {
+        let mut sup = supervisor(&[("a", &["nothing"])]);
+        assert_eq!(
+            sup.plan(&ids(&["a"])).unwrap_err(),
+            "missing dependencies (a needs nothing)"
+        );
+    }
+    #[test]
+    fn cycle_leaves_out_the_way_in() {
+        let mut sup = supervisor(&[("a", &["b"]), ("b", &["c"]), ("c", &["b"])]);
+        assert_eq!(
+            sup.plan(&ids(&["a"])).unwrap_err(),
+            "dependency cycle (b -> c -> b)"
+        );
+    }
+    #[test]
+    fn decodes_exit_statuses() {
+        assert_eq!(decode_status(0), ExitStatus::Exited { code: 0 });
+        assert_eq!(decode_status(3 << 8), ExitStatus::Exited { code: 3 });
+        assert_eq!(
+            decode_status(SIGKILL),
+            ExitStatus::Killed {
+                signal: SIGKILL,
+                core_dumped: false
+            }
+        );
+        assert_eq!(
+            decode_status(SIGABRT | 0x80),
+            ExitStatus::Killed {
+                signal: SIGABRT,
+                core_dumped: true
+            }
+        );
+    }
+    #[test]
+    fn restart_policies() {
+        let exited = |code| ExitStatus::Exited { code };
+        let killed = |signal| ExitStatus::Killed {
+            signal,
+            core_dumped: false,
+        };
+        let policy = |restart| {
+            service(
+                "a",
+                ServiceConfig {
+                    restart,
+                    success_exit_codes: vec![0, 2],
+                    ..Default::default()
+                },
+            )
+        };
+        let always = policy(Restart::Always);
+        assert!(always.wants_restart(exited(0)));
+        assert!(always.wants_restart(killed(SIGTERM)));
+        let on_failure = policy(Restart::OnFailure);
+        assert!(!on_failure.wants_restart(exited(0)));
+        assert!(!on_failure.wants_restart(exited(2)));
+        assert!(on_failure.wants_restart(exited(1)));
+        assert!(!on_failure.wants_restart(killed(SIGTERM)));
+        assert!(on_failure.wants_restart(killed(SIGKILL)));
+        let on_abnormal = policy(Restart::OnAbnormal);
+        assert!(!on_abnormal.wants_restart(exited(1)));
+        assert!(!on_abnormal.wants_restart(killed(SIGHUP)));
+        assert!(on_abnormal.wants_restart(killed(SIGABRT)));
+        let never = policy(Restart::Never);
+        assert!(!never.wants_restart(exited(1)));
+        assert!(!never.wants_restart(killed(SIGKILL)));
+    }
+}
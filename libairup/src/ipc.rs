@@ -0,0 +1,452 @@
+use nng::{
+    options::{Options, RecvTimeout, SendTimeout},
+    Protocol, Socket,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Version 1 has not been released, so it still changes in place. Once it
+// ships, bump this whenever a request or reply changes shape.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const DEFAULT_ADDRESS: &str = "ipc:///run/airup/airupd.sock";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+    pub version: u32,
+    pub id: u64,
+    pub method: Method,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Method {
+    Start {
+        svc: String,
+    },
+    Stop {
+        svc: String,
+    },
+    Restart {
+        svc: String,
+    },
+    Status {
+        svc: String,
+    },
+    List,
+    Power {
+        action: PowerAction,
+    },
+    Orphans,
+    // Read the service definitions under airup_home again.
+    Reload,
+    // Captured output: the lines from sequence number `since` on, or else
+    // the last `lines` lines.
+    Logs {
+        svc: String,
+        since: Option<u64>,
+        lines: Option<u32>,
+    },
+    // Reach `milestone`, stopping the services it does not need.
+    SwitchMilestone {
+        milestone: String,
+    },
+    Milestones,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerAction {
+    Poweroff,
+    Reboot,
+    Halt,
+    // Boot the kernel loaded for kexec without going through firmware.
+    Kexec,
+    // Start userspace over, keeping the running kernel.
+    SoftReboot,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response {
+    pub version: u32,
+    pub id: u64,
+    pub result: Result<Reply, Error>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Done,
+    Status(SvcInfo),
+    List {
+        services: Vec<SvcInfo>,
+    },
+    Orphans {
+        count: u64,
+    },
+    // `next` is the `since` to ask for to get only newer lines.
+    Logs {
+        lines: Vec<String>,
+        next: u64,
+    },
+    // Services by what a reload did to them. Those needing a restart only
+    // run with their new definition once restarted.
+    Reload {
+        added: Vec<String>,
+        changed: Vec<String>,
+        needs_restart: Vec<String>,
+        failed: Vec<String>,
+    },
+    // `target` is the milestone last asked for, `reached` those reached
+    // on the way to it so far.
+    Milestones {
+        stage: String,
+        target: String,
+        reached: Vec<String>,
+    },
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SvcInfo {
+    pub svc: String,
+    pub status: SvcStatus,
+    pub pid: i32,
+    // What the service last reported with STATUS=, if anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    // How the main process last exited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_exit: Option<ExitStatus>,
+    // Why it last failed to come up or stay up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+    // Sandboxing options the service runs with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sandbox: Vec<String>,
+    // Its definition changed in a way only a restart applies.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub needs_restart: bool,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "how", rename_all = "snake_case")]
+pub enum ExitStatus {
+    Exited { code: i32 },
+    Killed { signal: i32, core_dumped: bool },
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SvcStatus {
+    Readying,
+    Running,
+    Restarting,
+    Stopping,
+    Stopped,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    UnsupportedVersion,
+    NoSuchService,
+    NoSuchMilestone,
+    PermissionDenied,
+    // A valid request that could not be carried out.
+    Failed,
+    Internal,
+    // Raised on the client side only.
+    Unreachable,
+}
+
+// The control socket address; AIRUP_SOCKET overrides it, which is mostly
+// useful for quickdbg instances.
+pub fn address() -> String {
+    env::var("AIRUP_SOCKET").unwrap_or_else(|_| DEFAULT_ADDRESS.to_string())
+}
+fn bad_request(id: u64, e: serde_json::Error) -> Box<Response> {
+    Box::new(Response::new(
+        id,
+        Err(Error::new(ErrorCode::BadRequest, e.to_string())),
+    ))
+}
+
+impl Request {
+    pub fn new(method: Method) -> Request {
+        Request {
+            version: PROTOCOL_VERSION,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            method,
+        }
+    }
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    // A request we cannot parse or whose version we do not speak is
+    // answered with an error response carrying as much of its id as we know.
+    pub fn from_slice(buf: &[u8]) -> Result<Request, Box<Response>> {
+        let value: serde_json::Value =
+            serde_json::from_slice(buf).map_err(|e| bad_request(0, e))?;
+        let id = value.get("id").and_then(|a| a.as_u64()).unwrap_or(0);
+        let version = value.get("version").and_then(|a| a.as_u64()).unwrap_or(0);
+        if version != PROTOCOL_VERSION as u64 {
+            return Err(Box::new(Response::new(
+                id,
+                Err(Error::new(
+                    ErrorCode::UnsupportedVersion,
+                    format!(
+                        "protocol version {} is not supported, expected {}",
+                        version, PROTOCOL_VERSION
+                    ),
+                )),
+            )));
+        }
+        serde_json::from_value(value).map_err(|e| bad_request(id, e))
+    }
+}
+impl Response {
+    pub fn new(id: u64, result: Result<Reply, Error>) -> Response {
+        Response {
+            version: PROTOCOL_VERSION,
+            id,
+            result,
+        }
+    }
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+    pub fn from_slice(buf: &[u8]) -> Result<Response, Error> {
+        serde_json::from_slice(buf).map_err(|e| Error::new(ErrorCode::BadRequest, e.to_string()))
+    }
+}
+impl Error {
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Error {
+        Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl std::error::Error for Error {}
+impl Display for SvcStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            SvcStatus::Readying => "Readying",
+            SvcStatus::Running => "Running",
+            SvcStatus::Restarting => "Restarting",
+            SvcStatus::Stopping => "Stopping",
+            SvcStatus::Stopped => "Stopped",
+        };
+        write!(f, "{}", s)
+    }
+}
+impl Display for ExitStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitStatus::Exited { code } => write!(f, "exited with code {}", code),
+            ExitStatus::Killed {
+                signal,
+                core_dumped: false,
+            } => write!(f, "was killed by signal {}", signal),
+            ExitStatus::Killed {
+                signal,
+                core_dumped: true,
+            } => write!(f, "was killed by signal {} (core dumped)", signal),
+        }
+    }
+}
+
+// A connection to airupd's control socket.
+pub struct Client {
+    socket: Socket,
+}
+impl Client {
+    pub fn connect(addr: &str) -> Result<Client, Error> {
+        let unreachable = |e: nng::Error| {
+            Error::new(
+                ErrorCode::Unreachable,
+                format!("cannot connect to airupd at {}: {}", addr, e),
+            )
+        };
+        let socket = Socket::new(Protocol::Req0).map_err(unreachable)?;
+        socket
+            .set_opt::<RecvTimeout>(Some(Duration::from_secs(10)))
+            .map_err(unreachable)?;
+        socket
+            .set_opt::<SendTimeout>(Some(Duration::from_secs(10)))
+            .map_err(unreachable)?;
+        socket.dial(addr).map_err(unreachable)?;
+        Ok(Client { socket })
+    }
+    pub fn call(&self, method: Method) -> Result<Reply, Error> {
+        let unreachable =
+            |e: nng::Error| Error::new(ErrorCode::Unreachable, format!("airupd: {}", e));
+        let req = Request::new(method);
+        self.socket
+            .send(req.to_vec().as_slice())
+            .map_err(|(_, e)| unreachable(e))?;
+        let msg = self.socket.recv().map_err(unreachable)?;
+        let resp = Response::from_slice(msg.as_slice())?;
+        if resp.id != req.id {
+            return Err(Error::new(
+                ErrorCode::Internal,
+                "airupd answered a different request",
+            ));
+        }
+        resp.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> SvcInfo {
+        SvcInfo {
+            svc: "sshd".to_string(),
+            status: SvcStatus::Running,
+            pid: 42,
+            status_text: Some("Listening".to_string()),
+            last_exit: Some(ExitStatus::Killed {
+                signal: 9,
+                core_dumped: true,
+            }),
+            failure: Some("did not report readiness in time".to_string()),
+            sandbox: vec!["private_tmp".to_string()],
+            needs_restart: true,
+        }
+    }
+    fn svc() -> String {
+        "sshd".to_string()
+    }
+
+    #[test]
+    fn methods_round_trip() {
+        let methods = vec![
+            Method::Start { svc: svc() },
+            Method::Stop { svc: svc() },
+            Method::Restart { svc: svc() },
+            Method::Status { svc: svc() },
+            Method::List,
+            Method::Power {
+                action: PowerAction::Poweroff,
+            },
+            Method::Power {
+                action: PowerAction::Reboot,
+            },
+            Method::Power {
+                action: PowerAction::Halt,
+            },
+            Method::Power {
+                action: PowerAction::Kexec,
+            },
+            Method::Power {
+                action: PowerAction::SoftReboot,
+            },
+            Method::Orphans,
+            Method::Reload,
+            Method::Logs {
+                svc: svc(),
+                since: Some(7),
+                lines: None,
+            },
+            Method::SwitchMilestone {
+                milestone: "rescue".to_string(),
+            },
+            Method::Milestones,
+        ];
+        for method in methods {
+            let req = Request::new(method.clone());
+            let back = Request::from_slice(&req.to_vec()).unwrap();
+            assert_eq!(back.id, req.id);
+            assert_eq!(back.method, method);
+        }
+    }
+    #[test]
+    fn replies_round_trip() {
+        let bare = SvcInfo {
+            status: SvcStatus::Stopped,
+            pid: 0,
+            status_text: None,
+            last_exit: Some(ExitStatus::Exited { code: 1 }),
+            failure: None,
+            sandbox: Vec::new(),
+            needs_restart: false,
+            ..info()
+        };
+        let replies = vec![
+            Reply::Done,
+            Reply::Status(info()),
+            Reply::Status(bare.clone()),
+            Reply::List {
+                services: vec![info(), bare],
+            },
+            Reply::Orphans { count: 3 },
+            Reply::Logs {
+                lines: vec!["one".to_string(), "two".to_string()],
+                next: 9,
+            },
+            Reply::Reload {
+                added: vec!["a".to_string()],
+                changed: vec!["b".to_string()],
+                needs_restart: vec!["b".to_string()],
+                failed: vec!["c".to_string()],
+            },
+            Reply::Milestones {
+                stage: "Milestones(default)".to_string(),
+                target: "default".to_string(),
+                reached: vec!["basic".to_string()],
+            },
+        ];
+        for reply in replies {
+            let resp = Response::new(5, Ok(reply.clone()));
+            let back = Response::from_slice(&resp.to_vec()).unwrap();
+            assert_eq!((back.version, back.id), (PROTOCOL_VERSION, 5));
+            assert_eq!(back.result, Ok(reply));
+        }
+        let err = Error::new(ErrorCode::NoSuchService, "service x does not exist");
+        let resp = Response::new(6, Err(err.clone()));
+        assert_eq!(
+            Response::from_slice(&resp.to_vec()).unwrap().result,
+            Err(err)
+        );
+    }
+    #[test]
+    fn rejects_other_versions() {
+        let buf = br#"{"version":99,"id":12,"method":{"method":"list"}}"#;
+        let resp = Request::from_slice(buf).unwrap_err();
+        assert_eq!(resp.id, 12);
+        assert_eq!(resp.version, PROTOCOL_VERSION);
+        let err = resp.result.unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnsupportedVersion);
+        assert_eq!(
+            err.message,
+            format!(
+                "protocol version 99 is not supported, expected {}",
+                PROTOCOL_VERSION
+            )
+        );
+    }
+    #[test]
+    fn rejects_malformed_requests() {
+        let resp = Request::from_slice(b"not json").unwrap_err();
+        assert_eq!(resp.id, 0);
+        assert_eq!(resp.result.unwrap_err().code, ErrorCode::BadRequest);
+        let buf = format!(
+            r#"{{"version":{},"id":3,"method":{{"method":"frobnicate"}}}}"#,
+            PROTOCOL_VERSION
+        );
+        let resp = Request::from_slice(buf.as_bytes()).unwrap_err();
+        assert_eq!(resp.id, 3);
+        assert_eq!(resp.result.unwrap_err().code, ErrorCode::BadRequest);
+    }
+}